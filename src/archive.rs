@@ -0,0 +1,191 @@
+//! Snapshots from web archives that use the Wayback Machine's URL scheme,
+//! `<endpoint>/web/<timestamp>/<url>`, for pages whose origin is gone.
+//!
+//! The archive serves the nearest snapshot to the timestamp, with its own
+//! toolbar and scripts injected into the page, and with links rewritten to
+//! point into the archive. `strip_toolbar` undoes both.
+
+use anyhow::{Result, Context};
+use regex::{Captures, Regex};
+use lazy_static::lazy_static;
+use url::Url;
+use crate::date::DateTime;
+use crate::doc;
+
+pub static DEFAULT_ENDPOINT: &'static str = "https://web.archive.org/";
+
+/// The URL of the snapshot of `url` nearest to `secs`
+pub fn snapshot_url(endpoint: &Url, secs: u64, url: &Url) -> Result<Url> {
+    let path = format!("web/{}/{}", format_timestamp(secs), url);
+    endpoint.join(&path)
+        .with_context(|| format!("building snapshot url for {}", url))
+}
+
+/// Describes the snapshot served from `url`, for the document's metadata
+pub fn snapshot(url: &Url) -> doc::Snapshot {
+    let captured_at = url.path_segments()
+        .and_then(|mut segments| {
+            if segments.next() != Some("web") {
+                return None;
+            }
+            segments.next()
+        })
+        .and_then(parse_timestamp);
+    doc::Snapshot {
+        url: url.clone(),
+        captured_at,
+    }
+}
+
+/// Removes the markup the archive injects into a snapshot of an HTML page,
+/// and points links that were rewritten into the archive back at their
+/// original URLs. `snapshot` is the URL the page was served from.
+pub fn strip_toolbar(src: &str, snapshot: &Url) -> String {
+    lazy_static! {
+        static ref INJECTED: Vec<Regex> = [
+            // The toolbar at the top of the body
+            r"(?s)<!--\s*BEGIN WAYBACK TOOLBAR INSERT\s*-->.*?<!--\s*END WAYBACK TOOLBAR INSERT\s*-->",
+            // Scripts and styles at the start of the head, up to the marker
+            // that ends them
+            r#"(?s)<script[^>]*src="[^"]*(?:/_static/|archive\.org/includes/)[^"]*".*?<!--\s*End Wayback Rewrite JS Include\s*-->"#,
+            // The same, where the marker is missing
+            r#"(?s)<script[^>]*src="[^"]*(?:/_static/|archive\.org/includes/)[^"]*"[^>]*>\s*</script>"#,
+            r#"(?s)<script[^>]*>[^<]*(?:__wm\.|RufflePlayer)[^<]*</script>"#,
+            r#"<link[^>]*href="[^"]*/_static/[^"]*"[^>]*>"#,
+            // Comments about the capture at the end of the page
+            r"(?s)<!--\s*FILE ARCHIVED ON.*?-->",
+            r"(?s)<!--\s*playback timings.*?-->",
+        ].iter().map(|pattern| Regex::new(pattern).expect("bad regex")).collect();
+        // Rewritten links look like `/web/20200102030405im_/http://...`,
+        // either relative or on the archive's host
+        static ref REWRITTEN: Regex = Regex::new(
+            r#"(?:(?:https?:)?//([^/\s"'<>]+))?/web/\d{1,14}(?:[a-z]{2}_)?/(https?:)?//"#
+        ).expect("bad regex");
+    }
+
+    let mut src = src.to_string();
+    for regex in INJECTED.iter() {
+        src = regex.replace_all(&src, "").into_owned();
+    }
+
+    let host = match (snapshot.host_str(), snapshot.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => String::new(),
+    };
+    REWRITTEN.replace_all(&src, |caps: &Captures| {
+        match caps.get(1) {
+            // A link on another site that happens to look like one
+            Some(link_host) if !link_host.as_str().eq_ignore_ascii_case(&host) => {
+                caps[0].to_string()
+            }
+            _ => format!("{}//", caps.get(2).map(|scheme| scheme.as_str()).unwrap_or_default()),
+        }
+    }).into_owned()
+}
+
+/// Formats seconds since the Unix epoch as an archive timestamp, e.g.
+/// `20200102030405`
+fn format_timestamp(secs: u64) -> String {
+    let date = DateTime::from_secs(secs);
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}",
+            date.year, date.month, date.day,
+            date.hour, date.minute, date.second)
+}
+
+/// Parses an archive timestamp, which may be followed by a modifier like
+/// `im_`. Archives accept timestamps truncated to any precision.
+fn parse_timestamp(s: &str) -> Option<DateTime> {
+    let digits: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let num = |range: std::ops::Range<usize>, default: u32| -> Option<u32> {
+        match digits.get(range) {
+            Some(part) => part.parse().ok(),
+            None => Some(default),
+        }
+    };
+    if digits.len() < 4 {
+        return None;
+    }
+    let date = DateTime {
+        year: num(0..4, 0)? as i64,
+        month: num(4..6, 1)?,
+        day: num(6..8, 1)?,
+        hour: num(8..10, 0)?,
+        minute: num(10..12, 0)?,
+        second: num(12..14, 0)?,
+    };
+    if date.month < 1 || date.month > 12 || date.day < 1 || date.day > 31 {
+        return None;
+    }
+    Some(date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(src: &str) -> String {
+        let snapshot = Url::parse("https://web.archive.org/web/20200102030405/https://example.com/post").unwrap();
+        strip_toolbar(src, &snapshot)
+    }
+
+    #[test]
+    fn strips_injected_markup() {
+        let src = r#"<html><head><script src="//archive.org/includes/analytics.js"></script>
+<script type="text/javascript" src="/_static/js/bundle-playback.js"></script>
+<link rel="stylesheet" href="/_static/css/banner-styles.css">
+<!-- End Wayback Rewrite JS Include -->
+<title>Post</title></head><body>
+<!-- BEGIN WAYBACK TOOLBAR INSERT --><div id="wm-ipp">toolbar</div><!-- END WAYBACK TOOLBAR INSERT -->
+<p>Text</p>
+</body></html>
+<!--
+     FILE ARCHIVED ON 03:04:05 Jan 02, 2020 AND RETRIEVED FROM THE
+     INTERNET ARCHIVE ON 10:00:00 Feb 01, 2021.
+-->
+<!--
+playback timings (ms):
+  captures_list: 0.5
+-->"#;
+        let stripped = strip(src);
+        assert!(!stripped.contains("archive.org/includes"));
+        assert!(!stripped.contains("_static"));
+        assert!(!stripped.contains("toolbar"));
+        assert!(!stripped.contains("ARCHIVED"));
+        assert!(!stripped.contains("playback"));
+        assert!(stripped.contains("<title>Post</title>"));
+        assert!(stripped.contains("<p>Text</p>"));
+    }
+
+    #[test]
+    fn restores_rewritten_links() {
+        assert_eq!(strip(r#"<a href="/web/20200102030405/https://example.com/a">"#),
+                   r#"<a href="https://example.com/a">"#);
+        assert_eq!(strip(r#"<img src="/web/20200102030405im_/http://example.com/a.png">"#),
+                   r#"<img src="http://example.com/a.png">"#);
+        assert_eq!(strip(r#"<a href="https://web.archive.org/web/2020/https://example.com/a">"#),
+                   r#"<a href="https://example.com/a">"#);
+        assert_eq!(strip(r#"<a href="//web.archive.org/web/20200102030405/https://example.com/a">"#),
+                   r#"<a href="https://example.com/a">"#);
+        // Scheme-relative originals stay scheme-relative
+        assert_eq!(strip(r#"<script src="/web/20200102030405js_///cdn.example.com/a.js">"#),
+                   r#"<script src="//cdn.example.com/a.js">"#);
+    }
+
+    #[test]
+    fn keeps_other_links() {
+        let src = r#"<a href="https://example.com/web/2020/https://other.com/">
+<a href="/web/archive.html"><a href="https://other.com/a">"#;
+        assert_eq!(strip(src), src);
+    }
+
+    #[test]
+    fn snapshot_timestamps() {
+        let endpoint = Url::parse("http://127.0.0.1:8080/").unwrap();
+        let url = Url::parse("https://example.com/post").unwrap();
+        let snapshot = snapshot_url(&endpoint, 1577934245, &url).unwrap();
+        assert_eq!(snapshot.as_str(), "http://127.0.0.1:8080/web/20200102030405/https://example.com/post");
+        assert_eq!(super::snapshot(&snapshot).captured_at,
+                   Some(DateTime { year: 2020, month: 1, day: 2, hour: 3, minute: 4, second: 5 }));
+    }
+}
@@ -19,6 +19,17 @@ pub fn create_author_maps(config: &Config) -> Result<AuthorMaps> {
     let mut author_blog_posts = BTreeMap::new();
 
     for post in &config.blog_posts {
+        // A post's author override wins over the blog it's on. Names that
+        // aren't configured authors have no page, so aren't mapped.
+        if let Some(name) = &post.author {
+            if config.authors.iter().any(|author| &author.name == name) {
+                blog_post_author.insert(post.url.clone(), name.clone());
+                author_blog_posts.entry(name.clone())
+                    .or_insert_with(BTreeSet::new)
+                    .insert(post.url.clone());
+            }
+            continue;
+        }
         for author in &config.authors {
             if let Some(blog_url) = &author.blog {
                 if post.url.as_str().starts_with(blog_url.as_str()) {
@@ -42,9 +53,21 @@ pub fn create_author_maps(config: &Config) -> Result<AuthorMaps> {
     })
 }
 
+/// The author whose blog the URL is on, if any, as assigned by
+/// `create_author_maps`
+pub fn url_author<'a>(authors: &'a [Author], url: &Url) -> Option<&'a Author> {
+    authors.iter()
+        .rev()
+        .find(|author| {
+            author.blog.as_ref()
+                .map(|blog_url| url.as_str().starts_with(blog_url.as_str()))
+                .unwrap_or(false)
+        })
+}
+
 pub type AuthorName = String;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AuthorMaps {
     pub blog_post_author: BTreeMap<Url, AuthorName>,
     pub author_blog_posts: BTreeMap<AuthorName, BTreeSet<Url>>,
@@ -72,6 +95,7 @@ fn write_author_page(dir: &Path, author: &Author, assets: &AssetDirs, index_data
 
     let header_meta = render::HeaderMeta {
         title: Some("The Rust Docuniverse".to_string()),
+        ..Default::default()
     };
     
     render::render_head(&mut file, assets, &header_meta);
@@ -113,8 +137,7 @@ fn render_body(file: &mut File, author: &Author, index_data: &Vec<IndexEntry>, a
             continue;
         }
         writeln!(file, "<div>");
-        let title_slug = sanitize::title_to_slug(entry.title.clone());
-        writeln!(file, "<p><a href='../p/{}.html'>{}</a></p>", title_slug, entry.title);
+        writeln!(file, "<p><a href='../p/{}.html'>{}</a></p>", entry.file_name, entry.title);
         writeln!(file, "</div>");
     }
     writeln!(file, "</div>");
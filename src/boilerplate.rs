@@ -0,0 +1,69 @@
+//! Removal of the parts of an article that aren't the post itself: share
+//! buttons, comment sections, tag lists, subscribe boxes, bylines and the
+//! like, which sites put inside the same element as the post.
+
+use std::iter;
+use regex::Regex;
+use markup5ever_rcdom::{Handle, NodeData};
+use crate::html;
+
+/// Elements that are never part of the post
+static BOILERPLATE_TAGS: &[&str] = &["nav", "aside", "footer", "form", "script"];
+
+/// Matched against each class and the id. The words must be whole, or joined
+/// to others by `-` or `_`, so that e.g. `commentary` doesn't match.
+static BOILERPLATE_WORDS: &[&str] = &[
+    "share", "sharing", "sharedaddy", "social",
+    "comment", "comments", "respond",
+    "tags", "tag-links",
+    "subscribe", "subscription", "newsletter",
+    "byline", "author-bio",
+    "related", "relatedposts",
+];
+
+/// Elements with more than this share of the article's text are kept even
+/// if they look like boilerplate, since the post is likely inside them
+static MAX_SHARE: f64 = 0.5;
+
+/// Removes boilerplate elements from under `root`, returning descriptions of
+/// what was removed
+pub fn strip(root: &Handle) -> Vec<String> {
+    let names = format!("(?i)(?:^|[-_])(?:{})(?:[-_]|$)", BOILERPLATE_WORDS.join("|"));
+    let names = Regex::new(&names).expect("bad regex");
+    let total = html::text(root).chars().count();
+    let mut removed = Vec::new();
+    strip_children(root, &names, total, &mut removed);
+    removed
+}
+
+fn strip_children(node: &Handle, names: &Regex, total: usize, removed: &mut Vec<String>) {
+    node.children.borrow_mut().retain(|child| {
+        if !is_boilerplate(child, names) {
+            return true;
+        }
+        let len = html::text(child).chars().count();
+        if total > 0 && len as f64 > total as f64 * MAX_SHARE {
+            return true;
+        }
+        removed.push(format!("{} ({} chars)", html::describe(child), len));
+        false
+    });
+    for child in node.children.borrow().iter() {
+        strip_children(child, names, total, removed);
+    }
+}
+
+fn is_boilerplate(node: &Handle, names: &Regex) -> bool {
+    let (name, attrs) = match &node.data {
+        NodeData::Element { name, attrs, .. } => (name, attrs),
+        _ => return false,
+    };
+    if BOILERPLATE_TAGS.contains(&name.local.as_ref()) {
+        return true;
+    }
+    let id = html::find_attr(attrs, "id").unwrap_or_default();
+    let class = html::find_attr(attrs, "class").unwrap_or_default();
+    class.split_whitespace()
+        .chain(iter::once(id.as_str()))
+        .any(|name| !name.is_empty() && names.is_match(name))
+}
@@ -0,0 +1,311 @@
+//! The `cache` subcommands, for inspecting and maintaining the HTTP cache
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use log::{info, warn};
+use anyhow::{Result, Context, bail};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use url::Url;
+use crate::config::BlogPost;
+use crate::date;
+use crate::http_cache::{self, HttpCache, CacheEntry, CacheMeta, content_hash, url_hash};
+
+pub fn ls(cache: &HttpCache) -> Result<()> {
+    let mut total = 0;
+    let mut count = 0;
+
+    info!("{:>10} {:>16}  {}", "size", "fetched", "url");
+    for entry in cache.entries()? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let size = entry.size.unwrap_or(0);
+        let (fetched, url) = match &entry.meta {
+            Ok(Some(meta)) => (date::format_date(meta.fetched_at), meta.url.to_string()),
+            Ok(None) => ("-".to_string(), format!("({}, no metadata)", entry.hash)),
+            Err(_) => ("-".to_string(), format!("({}, bad metadata)", entry.hash)),
+        };
+        info!("{:>10} {:>16}  {}", size, fetched, url);
+        total += size;
+        count += 1;
+    }
+    info!("{} entries, {} bytes", count, total);
+
+    Ok(())
+}
+
+/// Checks that every post is cached, and that no entry is corrupt. Fails if
+/// there are any problems.
+pub fn verify(cache: &HttpCache, posts: &[&BlogPost]) -> Result<()> {
+    let mut problems = 0;
+    let mut problem = |msg: String| {
+        warn!("{}", msg);
+        problems += 1;
+    };
+
+    let mut no_meta = 0;
+    let mut cached = HashSet::new();
+    for entry in cache.entries()? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(name) => {
+                problem(format!("unexpected file {}", name));
+                continue;
+            }
+        };
+        cached.insert(entry.hash.clone());
+        match (&entry.meta, entry.size) {
+            (Err(e), _) => {
+                problem(format!("{}: {}", entry.hash, e));
+            }
+            (Ok(None), _) => {
+                no_meta += 1;
+            }
+            (Ok(Some(meta)), None) => {
+                problem(format!("{}: metadata for {} without a body", entry.hash, meta.url));
+            }
+            (Ok(Some(meta)), Some(_)) => {
+                if url_hash(&meta.url) != entry.hash {
+                    problem(format!("{}: entry for {} under the wrong name", entry.hash, meta.url));
+                }
+                if let Some(body_hash) = &meta.body_hash {
+                    let body = cache.read_entry(&entry.hash)?;
+                    if &content_hash(&body) != body_hash {
+                        problem(format!("{}: body of {} doesn't match its hash", entry.hash, meta.url));
+                    }
+                }
+            }
+        }
+    }
+
+    for post in posts {
+        if !cached.contains(&url_hash(&post.url)) {
+            problem(format!("not cached: {}", post.url));
+        }
+    }
+
+    if no_meta > 0 {
+        info!("{} entries have no metadata, and can't be verified", no_meta);
+    }
+    if problems > 0 {
+        bail!("{} problems in the cache", problems);
+    }
+
+    info!("cache ok");
+    Ok(())
+}
+
+/// Removes pages whose URLs aren't configured posts. Images and other
+/// resources are kept, as they belong to the posts that link them. Entries
+/// without metadata, from before it was recorded, can't be told apart, so
+/// they are listed as unknown, and only removed with `include_legacy`,
+/// unless they are the page of a configured post.
+pub fn prune(cache: &HttpCache, posts: &[BlogPost], dry_run: bool, include_legacy: bool) -> Result<()> {
+    let configured: HashSet<&Url> = posts.iter()
+        .map(|post| &post.url)
+        .collect();
+    let configured_hashes: HashSet<String> = posts.iter()
+        .map(|post| url_hash(&post.url))
+        .collect();
+    let mut pruned = 0;
+    let mut unknown = 0;
+
+    for entry in cache.entries()? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        match &entry.meta {
+            Ok(Some(meta)) => {
+                if configured.contains(&meta.url) || !is_page(meta) {
+                    continue;
+                }
+                info!("pruning {}", meta.url);
+            }
+            Ok(None) => {
+                if configured_hashes.contains(&entry.hash) {
+                    continue;
+                }
+                unknown += 1;
+                if !include_legacy {
+                    info!("unknown {} (no metadata)", entry.hash);
+                    continue;
+                }
+                info!("pruning unknown {} (no metadata)", entry.hash);
+            }
+            Err(e) => {
+                warn!("skipping {}: {}", entry.hash, e);
+                continue;
+            }
+        }
+        if !dry_run {
+            cache.remove(&entry.hash)?;
+        }
+        pruned += 1;
+    }
+
+    if dry_run {
+        info!("would prune {} entries", pruned);
+    } else {
+        info!("pruned {} entries", pruned);
+    }
+    if unknown > 0 && !include_legacy {
+        info!("kept {} unknown entries without metadata, use --include-legacy to remove them", unknown);
+    }
+    Ok(())
+}
+
+fn is_page(meta: &CacheMeta) -> bool {
+    match &meta.content_type {
+        Some(content_type) => {
+            let content_type = content_type.to_ascii_lowercase();
+            content_type.starts_with("text/html")
+                || content_type.starts_with("application/xhtml")
+        }
+        None => false,
+    }
+}
+
+/// Writes every entry, with its metadata, to a gzipped tar file
+pub fn export(cache: &HttpCache, file: &Path) -> Result<()> {
+    let out = File::create(file)
+        .context("creating archive")?;
+    let mut archive = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    let mut count = 0;
+
+    for entry in cache.entries()? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        if entry.size.is_none() {
+            continue;
+        }
+        let meta_name = format!("{}.toml", entry.hash);
+        archive.append_path_with_name(cache.dir().join(&entry.hash), &entry.hash)
+            .context("archiving cache entry")?;
+        if cache.dir().join(&meta_name).exists() {
+            archive.append_path_with_name(cache.dir().join(&meta_name), &meta_name)
+                .context("archiving cache metadata")?;
+        }
+        count += 1;
+    }
+
+    archive.into_inner()
+        .context("writing archive")?
+        .finish()
+        .context("writing archive")?;
+    info!("exported {} entries to {}", count, file.display());
+    Ok(())
+}
+
+/// Adds the entries of an archive written by `export` to the cache. Entries
+/// already in the cache are only replaced if the archived copy was fetched
+/// more recently. Entries that don't match their metadata are skipped.
+pub fn import(cache: &HttpCache, file: &Path) -> Result<()> {
+    // Bodies come before their metadata in the archive, so the metadata is
+    // read first, and the bodies one at a time in a second pass
+    let mut metas = BTreeMap::new();
+    for_each_archived(file, |name, file| {
+        if let Some(hash) = name.strip_suffix(".toml").filter(|h| http_cache::is_hash(h)) {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)
+                .context("reading archive")?;
+            let meta: CacheMeta = toml::from_str(&String::from_utf8_lossy(&buf))
+                .with_context(|| format!("parsing archived metadata for {}", hash))?;
+            metas.insert(hash.to_string(), (meta, buf));
+        } else if !http_cache::is_hash(name) {
+            warn!("skipping unexpected file {} in archive", name);
+        }
+        Ok(())
+    })?;
+
+    let existing: BTreeMap<String, CacheEntry> = cache.entries()?.into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| (entry.hash.clone(), entry))
+        .collect();
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut mismatched = 0;
+
+    fs::create_dir_all(cache.dir())
+        .context("creating cache dir")?;
+    for_each_archived(file, |hash, file| {
+        if !http_cache::is_hash(hash) {
+            return Ok(());
+        }
+        let meta = metas.remove(hash);
+        let newer = match existing.get(hash) {
+            None => true,
+            Some(local) if local.size.is_none() => true,
+            Some(local) => match (&local.meta, &meta) {
+                (Ok(Some(local)), Some((meta, _))) => meta.fetched_at > local.fetched_at,
+                (Ok(Some(_)), None) => false,
+                // The local copy has no metadata, or it's corrupt
+                (_, meta) => meta.is_some(),
+            }
+        };
+        if !newer {
+            skipped += 1;
+            return Ok(());
+        }
+        let mut body = Vec::new();
+        file.read_to_end(&mut body)
+            .context("reading archive")?;
+        if let Some((meta, _)) = &meta {
+            if url_hash(&meta.url) != hash {
+                warn!("skipping {}: archived entry for {} under the wrong name", hash, meta.url);
+                mismatched += 1;
+                return Ok(());
+            }
+            if meta.body_hash.as_ref().map(|h| *h != content_hash(&body)).unwrap_or(false) {
+                warn!("skipping {}: archived body of {} doesn't match its hash", hash, meta.url);
+                mismatched += 1;
+                return Ok(());
+            }
+        }
+        http_cache::write_atomic(&cache.dir().join(hash), &body)
+            .context("writing cache entry")?;
+        let meta_path = cache.dir().join(format!("{}.toml", hash));
+        match meta {
+            Some((_, meta)) => {
+                http_cache::write_atomic(&meta_path, meta)
+                    .context("writing cache metadata")?;
+            }
+            None => {
+                if meta_path.exists() {
+                    fs::remove_file(&meta_path)
+                        .context("removing cache metadata")?;
+                }
+            }
+        }
+        imported += 1;
+        Ok(())
+    })?;
+
+    info!("imported {} entries, kept {} existing entries", imported, skipped);
+    if mismatched > 0 {
+        warn!("skipped {} entries that don't match their metadata", mismatched);
+    }
+    Ok(())
+}
+
+/// Calls `f` with the name and reader of each file in an archive written by
+/// `export`
+fn for_each_archived(file: &Path, mut f: impl FnMut(&str, &mut dyn Read) -> Result<()>) -> Result<()> {
+    let input = File::open(file)
+        .context("opening archive")?;
+    let mut archive = tar::Archive::new(GzDecoder::new(input));
+    for entry in archive.entries().context("reading archive")? {
+        let mut entry = entry.context("reading archive")?;
+        let name = entry.path().context("reading archive")?
+            .to_string_lossy().into_owned();
+        f(&name, &mut entry)?;
+    }
+    Ok(())
+}
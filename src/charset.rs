@@ -0,0 +1,77 @@
+//! Decoding of fetched pages to text. The encoding is taken from, in order
+//! of precedence, a byte order mark, the `Content-Type` header, a `<meta>`
+//! charset declaration near the start of the page, and otherwise guessed:
+//! UTF-8 if the page is valid UTF-8, windows-1252 if not, as that's what
+//! older pages without a declaration are almost always written in.
+
+use log::debug;
+use regex::bytes::Regex;
+use lazy_static::lazy_static;
+use encoding_rs::{Encoding, UTF_8, UTF_16LE, UTF_16BE, WINDOWS_1252};
+
+/// How far into the page to look for a `<meta>` declaration, as browsers do
+static PRESCAN_LEN: usize = 1024;
+
+pub fn decode(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = detect(bytes, content_type);
+    debug!("decoding page as {}", encoding.name());
+    // `decode` also strips any BOM
+    let (text, _, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        debug!("page has malformed {}", encoding.name());
+    }
+    text.into_owned()
+}
+
+pub fn detect(bytes: &[u8], content_type: Option<&str>) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if let Some(encoding) = content_type.and_then(header_charset) {
+        return encoding;
+    }
+    if let Some(encoding) = meta_charset(bytes) {
+        return encoding;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        UTF_8
+    } else {
+        WINDOWS_1252
+    }
+}
+
+/// The charset parameter of a `Content-Type` value
+fn header_charset(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next()?.trim();
+            let value = parts.next()?.trim().trim_matches(|c| c == '"' || c == '\'');
+            if name.eq_ignore_ascii_case("charset") {
+                Encoding::for_label(value.as_bytes())
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+/// Finds `<meta charset=..>` or `<meta http-equiv="Content-Type"
+/// content="..; charset=..">` in the start of the page
+fn meta_charset(bytes: &[u8]) -> Option<&'static Encoding> {
+    lazy_static! {
+        static ref META: Regex = Regex::new(r#"(?i-u)<meta\s[^>]*?charset\s*=\s*["']?\s*([a-z0-9_:.+-]+)"#)
+            .expect("bad regex");
+    }
+    let prescan = &bytes[..bytes.len().min(PRESCAN_LEN)];
+    let label = META.captures(prescan)?.get(1)?.as_bytes();
+    let encoding = Encoding::for_label(label)?;
+    // A page that can be read to find its declaration is not UTF-16,
+    // whatever it says
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        Some(UTF_8)
+    } else {
+        Some(encoding)
+    }
+}
@@ -1,12 +1,19 @@
 use std::fmt;
 use std::fs;
+use std::path::Path;
 use std::default::Default;
 use url::Url;
 use serde::{Serialize, Deserialize};
-use anyhow::{Result, Context};
+use std::collections::BTreeMap;
+use regex::Regex;
+use anyhow::{Result, Context, bail};
+use log::warn;
+use crate::date::DateTime;
+use crate::selector::Selector;
 
 static BLOG_POSTS_FILE: &'static str = "./config/blog-posts.toml";
 static AUTHORS_FILE: &'static str = "./config/authors.toml";
+static SITES_FILE: &'static str = "./config/sites.toml";
 
 pub fn load_config() -> Result<Config> {
     let blogs = fs::read_to_string(BLOG_POSTS_FILE)
@@ -19,16 +26,107 @@ pub fn load_config() -> Result<Config> {
     let authors: AuthorsConfig = toml::from_str(&authors)
         .context("parsing authors")?;
 
+    // Optional, as most hosts need no rules of their own
+    let sites = if Path::new(SITES_FILE).exists() {
+        let sites = fs::read_to_string(SITES_FILE)
+            .context("reading sites file")?;
+        let sites: SitesConfig = toml::from_str(&sites)
+            .context("parsing sites")?;
+        sites.sites
+    } else {
+        Vec::new()
+    };
+
+    validate(&blogs.blog_posts, &sites)?;
+
     Ok(Config {
         blog_posts: blogs.blog_posts,
         authors: authors.authors,
+        sites,
     })
 }
 
+/// Checks the overrides and selectors that are only used while building,
+/// so that mistakes are reported up front, all at once
+fn validate(posts: &[BlogPost], sites: &[Site]) -> Result<()> {
+    let mut problems = Vec::new();
+    let check_selector = |problems: &mut Vec<String>, s: &str, place: &dyn fmt::Display| {
+        if let Err(e) = Selector::parse(s) {
+            problems.push(format!("{:#} in the config for {}", e, place));
+        }
+    };
+
+    for post in posts {
+        if let Some(slug) = &post.slug {
+            // The slug becomes a file name and a link, so can't be just anything
+            if slug.is_empty() || !slug.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-') {
+                problems.push(format!("invalid slug '{}' in the config for {}: only a-z, 0-9 and - are allowed",
+                                      slug, post.url));
+            }
+        }
+        if let Some(date) = &post.date {
+            if DateTime::parse_iso8601(date).is_none() {
+                problems.push(format!("invalid date '{}' in the config for {}", date, post.url));
+            }
+        }
+        for selector in post.selector.iter().chain(&post.exclude) {
+            check_selector(&mut problems, selector, &post.url);
+        }
+    }
+    for site in sites {
+        for selector in site.article.iter().chain(&site.remove).chain(&site.title) {
+            check_selector(&mut problems, selector, &site.host);
+        }
+    }
+
+    for problem in &problems {
+        warn!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("{} problems in the config", problems.len());
+    }
+    Ok(())
+}
+
+/// Replaces post URLs in the blog posts file, leaving the rest of the file
+/// as written. Returns the number of URLs replaced.
+pub fn rewrite_post_urls(renames: &BTreeMap<Url, Url>) -> Result<usize> {
+    let blogs = fs::read_to_string(BLOG_POSTS_FILE)
+        .context("reading blog file")?;
+    let url_line = Regex::new(r#"^(\s*url\s*=\s*")([^"]*)(".*)$"#)
+        .expect("bad regex");
+
+    let mut count = 0;
+    let mut lines = Vec::new();
+    for line in blogs.lines() {
+        let renamed = url_line.captures(line).and_then(|caps| {
+            let url = Url::parse(&caps[2]).ok()?;
+            let new_url = renames.get(&url)?;
+            Some(format!("{}{}{}", &caps[1], new_url, &caps[3]))
+        });
+        match renamed {
+            Some(renamed) => {
+                lines.push(renamed);
+                count += 1;
+            }
+            None => {
+                lines.push(line.to_string());
+            }
+        }
+    }
+
+    let mut blogs = lines.join("\n");
+    blogs.push('\n');
+    fs::write(BLOG_POSTS_FILE, blogs)
+        .context("writing blog file")?;
+    Ok(count)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub blog_posts: Vec<BlogPost>,
     pub authors: Vec<Author>,
+    pub sites: Vec<Site>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -43,6 +141,23 @@ pub struct BlogPost {
     pub category: Category,
     #[serde(default)]
     pub publish: bool,
+
+    // Overrides for when extraction gets the post wrong. These win over the
+    // page, the site rules and the author config.
+    pub title: Option<String>,
+    /// The file name of the rendered post, without `.html`
+    pub slug: Option<String>,
+    /// The name of an author in the authors config, or of someone else
+    pub author: Option<String>,
+    /// The publication date, in ISO 8601, e.g. "2020-05-03"
+    pub date: Option<String>,
+    /// The description, for the page's head
+    pub summary: Option<String>,
+    /// The root element of the article
+    pub selector: Option<String>,
+    /// Elements to remove from the article
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -120,3 +235,43 @@ pub struct Author {
     pub github: Option<String>,
     pub blog: Option<Url>,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SitesConfig {
+    #[serde(default)]
+    sites: Vec<Site>,
+}
+
+/// Extraction rules for the posts of a host. Selectors are CSS-style, as
+/// understood by `selector::Selector`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Site {
+    /// A host name, or `*.example.com` for a domain and its subdomains
+    pub host: String,
+    /// The root element of the article
+    pub article: Option<String>,
+    /// Elements to remove from the article, like share buttons
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// The element holding the post's title, anywhere in the page
+    pub title: Option<String>,
+}
+
+impl Site {
+    pub fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        };
+        let pattern = self.host.to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+            None => host == pattern,
+        }
+    }
+}
+
+/// The rules for a post's host. The first matching site wins.
+pub fn site_for<'a>(sites: &'a [Site], url: &Url) -> Option<&'a Site> {
+    sites.iter().find(|site| site.matches(url))
+}
@@ -1,39 +1,56 @@
 use url::Url;
+use std::fmt;
 use std::mem;
-use anyhow::Result;
+use anyhow::{Result, bail};
 use crate::html::SubDom;
 use markup5ever_rcdom as rcdom;
-use rcdom::{Node, NodeData};
+use rcdom::{Node, NodeData, Handle};
 use crate::doc;
 use log::{warn, debug, error};
 use crate::config::BlogPost;
+use crate::html;
+use crate::extract;
+use regex::{Regex, RegexSet};
+use lazy_static::lazy_static;
+use std::cell::RefCell;
+use html5ever::Attribute;
 
-pub fn from_dom(post: &BlogPost, dom: &SubDom) -> doc::Document {
-    let body = body_from_dom(dom);
+/// Converts the article to a document, along with diagnostics about content
+/// that couldn't be converted faithfully. Errors are only returned for
+/// failures of the converter itself, never for odd markup.
+pub fn from_dom(post: &BlogPost, dom: &SubDom) -> Result<(doc::Document, Vec<Diagnostic>)> {
+    let (body, diagnostics) = body_from_dom(dom, &post.url)?;
 
     let meta = doc::Meta {
         origin_url: post.url.clone(),
+        snapshot: None,
+        page: doc::PageMeta::default(),
     };
     let doc = doc::Document {
         meta, body
     };
 
-    doc
+    Ok((doc, diagnostics))
 }
 
-pub fn body_from_dom(dom: &SubDom) -> doc::Body {
+/// Converts the DOM to a document body. Relative URLs are resolved against
+/// `base_url`, the location the page was originally served from.
+pub fn body_from_dom(dom: &SubDom, base_url: &Url) -> Result<(doc::Body, Vec<Diagnostic>)> {
     let mut state = State {
         mode: Mode::AccumulateBlocks(Vec::new()),
+        base_url: base_url.clone(),
+        path: Vec::new(),
+        diagnostics: Vec::new(),
     };
 
-    walk(&mut state, &dom.1);
+    walk(&mut state, &dom.1)?;
 
     let blocks = match state.mode {
         Mode::AccumulateBlocks(blocks) => {
             blocks
         },
         _ => {
-            panic!("unexpected mode {:?}", state.mode);
+            bail!("unexpected mode {:?}", state.mode);
         }
     };
 
@@ -41,11 +58,90 @@ pub fn body_from_dom(dom: &SubDom) -> doc::Body {
         blocks: blocks,
     };
 
-    body
+    Ok((body, state.diagnostics))
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    /// Where in the DOM it happened, e.g. `article > div.entry > ul`
+    pub path: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum DiagnosticKind {
+    /// An element with no conversion of its own. Its children are still
+    /// converted.
+    SkippedElement(String),
+    /// An element that was discarded along with its content. Emphasis and
+    /// links outside of inline content are the exception: their content is
+    /// still converted, without the formatting or link.
+    DroppedElement(String),
+    /// Text in a context that only holds blocks, with its length in chars
+    DroppedText(usize),
+    /// A block element in a context that doesn't allow it. Its content was
+    /// flattened into the surrounding context.
+    UnexpectedNesting {
+        element: String,
+        context: &'static str,
+    },
+    /// Inline content where blocks were expected, wrapped in a paragraph
+    ImpliedParagraph,
+}
+
+impl DiagnosticKind {
+    /// A description of the kind without details, for grouping
+    pub fn summary(&self) -> &'static str {
+        match self {
+            DiagnosticKind::SkippedElement(_) => "skipped element",
+            DiagnosticKind::DroppedElement(_) => "dropped element",
+            DiagnosticKind::DroppedText(_) => "dropped text",
+            DiagnosticKind::UnexpectedNesting { .. } => "unexpected nesting",
+            DiagnosticKind::ImpliedParagraph => "implied paragraph",
+        }
+    }
+
+    /// Whether this is a skipped wrapper like `div` or `span`, which only
+    /// groups its children and so loses nothing when they are converted
+    pub fn is_transparent(&self) -> bool {
+        match self {
+            DiagnosticKind::SkippedElement(name) => TRANSPARENT_ELEMENTS.contains(&name.as_str()),
+            _ => false,
+        }
+    }
+}
+
+/// Elements that have no meaning of their own beyond grouping their content
+static TRANSPARENT_ELEMENTS: &[&str] = &["div", "span", "section", "article", "main"];
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            DiagnosticKind::SkippedElement(name) => {
+                write!(f, "skipped <{}>", name)?;
+            }
+            DiagnosticKind::DroppedElement(name) => {
+                write!(f, "dropped <{}>", name)?;
+            }
+            DiagnosticKind::DroppedText(len) => {
+                write!(f, "dropped {} chars of text", len)?;
+            }
+            DiagnosticKind::UnexpectedNesting { element, context } => {
+                write!(f, "flattened <{}> in {} context", element, context)?;
+            }
+            DiagnosticKind::ImpliedParagraph => {
+                write!(f, "wrapped inlines in a paragraph")?;
+            }
+        }
+        write!(f, " at {}", self.path)
+    }
 }
 
 struct State {
     mode: Mode,
+    base_url: Url,
+    path: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 #[derive(Debug)]
@@ -56,131 +152,203 @@ enum Mode {
     Placeholder,
 }
 
-fn walk(state: &mut State, node: &Node) {
+impl Mode {
+    fn context(&self) -> &'static str {
+        match self {
+            Mode::AccumulateInlines(_) => "inline",
+            Mode::AccumulateListItems(_) => "list",
+            Mode::AccumulateBlocks(_) => "block",
+            Mode::Placeholder => "placeholder",
+        }
+    }
+}
+
+fn diagnose(state: &mut State, kind: DiagnosticKind) {
+    let path = state.path.join(" > ");
+    state.diagnostics.push(Diagnostic { kind, path });
+}
+
+fn diagnose_nesting(state: &mut State, node: &Node, mode: &Mode) {
+    if let NodeData::Element { name, .. } = &node.data {
+        let kind = DiagnosticKind::UnexpectedNesting {
+            element: name.local.to_string(),
+            context: mode.context(),
+        };
+        diagnose(state, kind);
+    }
+}
+
+/// Describes an element for diagnostic paths, with its id or first class
+fn path_segment(node: &Node) -> String {
     match &node.data {
-        NodeData::Element { name, .. } => {
+        NodeData::Element { name, attrs, .. } => {
             let name = name.local.as_ref();
-            match name {
-                "p" => {
-                    handle_para(state, node);
-                    return;
-                },
-                "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
-                    handle_heading(state, node, name);
-                    return;
-                }
-                "ol" => {
-                    handle_list(state, node, doc::ListType::Ordered);
-                    return;
-                }
-                "ul" => {
-                    handle_list(state, node, doc::ListType::Unordered);
-                    return;
-                }
-                "li" => {
-                    handle_list_item(state, node);
-                    return;
-                }
-                "blockquote" => {
-                    handle_blockquote(state, node);
-                    return;
-                }
-                "hr" => {
-                    handle_thematic_break(state, node);
-                    return;
-                }
-                "pre" => {
-                    handle_pre(state, node);
-                    return;
-                }
-                "em" | "strong" | "i" | "b" | "code" => {
-                    handle_emph(state, node, name);
-                    return;
-                }
-                "div" => {
-                    handle_div(state, node);
-                }
-                _ => {
+            if let Some(id) = html::find_attr(attrs, "id") {
+                format!("{}#{}", name, id)
+            } else if let Some(class) = html::find_attr(attrs, "class") {
+                match class.split_whitespace().next() {
+                    Some(class) => format!("{}.{}", name, class),
+                    None => name.to_string(),
                 }
+            } else {
+                name.to_string()
             }
         }
+        _ => String::new(),
+    }
+}
+
+fn walk(state: &mut State, node: &Node) -> Result<()> {
+    match &node.data {
+        NodeData::Element { name, attrs, .. } => {
+            state.path.push(path_segment(node));
+            let res = walk_element(state, node, name.local.as_ref(), attrs);
+            state.path.pop();
+            res
+        }
         NodeData::Text { contents } => {
             let text = String::from(contents.borrow().as_ref());
-            handle_text(state, node, text);
-            return;
+            handle_text(state, node, text)
         }
         _ => {
+            walk_children(state, node)
         }
     }
+}
 
-    walk_children(state, node);
+fn walk_element(state: &mut State, node: &Node, name: &str, attrs: &RefCell<Vec<Attribute>>) -> Result<()> {
+    match name {
+        "p" => {
+            handle_para(state, node)
+        },
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            handle_heading(state, node, name)
+        }
+        "ol" => {
+            handle_list(state, node, doc::ListType::Ordered)
+        }
+        "ul" => {
+            handle_list(state, node, doc::ListType::Unordered)
+        }
+        "li" => {
+            handle_list_item(state, node)
+        }
+        "blockquote" => {
+            handle_blockquote(state, node)
+        }
+        "hr" => {
+            handle_thematic_break(state, node)
+        }
+        "pre" => {
+            handle_pre(state, node)
+        }
+        "em" | "strong" | "i" | "b" | "code" => {
+            handle_emph(state, node, name)
+        }
+        "a" => {
+            let href = html::find_attr(attrs, "href");
+            let title = html::find_attr(attrs, "title");
+            handle_link(state, node, href, title)
+        }
+        "img" => {
+            let image = image_from_attrs(state, attrs);
+            handle_image(state, image)
+        }
+        "picture" => {
+            let image = find_image(state, node);
+            handle_image(state, image)
+        }
+        "figure" => {
+            handle_figure(state, node)
+        }
+        "table" => {
+            handle_table(state, node)
+        }
+        "div" => {
+            handle_div(state, node);
+            diagnose_skipped(state, name);
+            walk_children(state, node)
+        }
+        _ => {
+            diagnose_skipped(state, name);
+            walk_children(state, node)
+        }
+    }
+}
+
+fn diagnose_skipped(state: &mut State, name: &str) {
+    // The root is just the container of the article
+    let is_root = state.path.len() == 1;
+    if !is_root {
+        diagnose(state, DiagnosticKind::SkippedElement(name.to_string()));
+    }
 }
 
-fn walk_children(state: &mut State, node: &Node) {
+fn walk_children(state: &mut State, node: &Node) -> Result<()> {
     for child in node.children.borrow().iter() {
-        walk(state, &child);
+        walk(state, &child)?;
     }
+    Ok(())
 }
 
 /// Our model requires the root, list items, and blockquotes to contain block
 /// items, where HTML allows them to contain inlines directly. This detects this
 /// situation and opens paragraph blocks that don't exist in the source HTML.
-fn walk_block_children(state: &mut State, node: &Node) {
+fn walk_block_children(state: &mut State, node: &Node) -> Result<()> {
     let need_block = match state.mode {
         Mode::AccumulateBlocks(_) => true,
         _ => false,
     };
-    assert!(need_block);
+    if !need_block {
+        bail!("walking block children in {} context", state.mode.context());
+    }
 
     let mut next_inlines = Vec::new();
-    
+
     for child in node.children.borrow().iter() {
         if is_inline_element(child) {
             let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
             state.mode = Mode::AccumulateInlines(Vec::new());
-            walk(state, child);
+            walk(state, child)?;
             let mode = mem::replace(&mut state.mode, Mode::Placeholder);
             match mode {
                 Mode::AccumulateInlines(inlines) => {
                     next_inlines.extend(inlines);
                     state.mode = old_mode;
                 }
-                _ => panic!("unexpected mode {:?}", mode),
+                _ => bail!("unexpected mode {:?}", mode),
             }
         } else {
             if !next_inlines.is_empty() {
-                let new_block = doc::Block::Paragraph(
-                    doc::Paragraph {
-                        inlines: next_inlines,
-                    }
-                );
+                push_implied_paragraph(state, next_inlines)?;
                 next_inlines = Vec::new();
-                match state.mode {
-                    Mode::AccumulateBlocks(ref mut blocks) => {
-                        blocks.push(new_block);
-                    }
-                    _ => panic!()
-                }
             }
 
-            walk(state, child)
+            walk(state, child)?;
         }
     }
 
     if !next_inlines.is_empty() {
-        let new_block = doc::Block::Paragraph(
-            doc::Paragraph {
-                inlines: next_inlines,
-            }
-        );
-        next_inlines = Vec::new();
-        match state.mode {
-            Mode::AccumulateBlocks(ref mut blocks) => {
-                blocks.push(new_block);
-            }
-            _ => panic!()
+        push_implied_paragraph(state, next_inlines)?;
+    }
+
+    Ok(())
+}
+
+fn push_implied_paragraph(state: &mut State, inlines: Vec<doc::Inline>) -> Result<()> {
+    let new_block = doc::Block::Paragraph(
+        doc::Paragraph {
+            inlines,
+        }
+    );
+    match state.mode {
+        Mode::AccumulateBlocks(ref mut blocks) => {
+            blocks.push(new_block);
         }
+        _ => bail!("unexpected mode {:?}", state.mode),
     }
+    diagnose(state, DiagnosticKind::ImpliedParagraph);
+    Ok(())
 }
 
 fn is_inline_element(node: &Node) -> bool {
@@ -206,12 +374,12 @@ fn is_inline_element(node: &Node) -> bool {
     }
 }
 
-fn handle_para(state: &mut State, node: &Node) {
+fn handle_para(state: &mut State, node: &Node) -> Result<()> {
     let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
     match old_mode {
         Mode::AccumulateBlocks(mut blocks) => {
             state.mode = Mode::AccumulateInlines(Vec::new());
-            walk_children(state, node);
+            walk_children(state, node)?;
             let mode = mem::replace(&mut state.mode, Mode::Placeholder);
             match mode {
                 Mode::AccumulateInlines(inlines) => {
@@ -220,22 +388,23 @@ fn handle_para(state: &mut State, node: &Node) {
                     blocks.push(new_block);
                     state.mode = Mode::AccumulateBlocks(blocks);
                 }
-                e => panic!("unexpected mode {:?}", e),
+                e => bail!("unexpected mode {:?}", e),
             }
         }
         _ => {
-            //warn!("unhandled para");
+            diagnose_nesting(state, node, &old_mode);
             state.mode = old_mode;
-            walk_children(state, node);
+            walk_children(state, node)?;
         }
     }
+    Ok(())
 }
 
 fn handle_div(state: &mut State, node: &Node) {
     // todo
 }
 
-fn handle_heading(state: &mut State, node: &Node, htext: &str) {
+fn handle_heading(state: &mut State, node: &Node, htext: &str) -> Result<()> {
     let level = match htext {
         "h1" => doc::HeadingLevel::H1,
         "h2" => doc::HeadingLevel::H2,
@@ -243,13 +412,13 @@ fn handle_heading(state: &mut State, node: &Node, htext: &str) {
         "h4" => doc::HeadingLevel::H4,
         "h5" => doc::HeadingLevel::H5,
         "h6" => doc::HeadingLevel::H6,
-        _ => panic!("unexpected heading level"),
+        _ => bail!("unexpected heading level {}", htext),
     };
     let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
     match old_mode {
         Mode::AccumulateBlocks(mut blocks) => {
             state.mode = Mode::AccumulateInlines(Vec::new());
-            walk_children(state, node);
+            walk_children(state, node)?;
             let mode = mem::replace(&mut state.mode, Mode::Placeholder);
             match mode {
                 Mode::AccumulateInlines(inlines) => {
@@ -261,36 +430,40 @@ fn handle_heading(state: &mut State, node: &Node, htext: &str) {
                     blocks.push(new_block);
                     state.mode = Mode::AccumulateBlocks(blocks);
                 }
-                e => panic!("unexpected mode {:?}", e),
+                e => bail!("unexpected mode {:?}", e),
             }
         }
         _ => {
-            //warn!("unhandled heading");
+            diagnose_nesting(state, node, &old_mode);
             state.mode = old_mode;
-            walk_children(state, node);
+            walk_children(state, node)?;
         }
     }
+    Ok(())
 }
 
-fn handle_text(state: &mut State, node: &Node, text: String) {
+fn handle_text(state: &mut State, node: &Node, text: String) -> Result<()> {
     match state.mode {
         Mode::AccumulateInlines(ref mut inlines) => {
             let new = doc::Inline::Text(text);
             inlines.push(new);
         }
         _ => {
-            //warn!("unhandled text");
+            let len = text.trim().chars().count();
+            if len > 0 {
+                diagnose(state, DiagnosticKind::DroppedText(len));
+            }
         }
     }
-    walk_children(state, node);
+    walk_children(state, node)
 }
 
-fn handle_list(state: &mut State, node: &Node, type_: doc::ListType) {
+fn handle_list(state: &mut State, node: &Node, type_: doc::ListType) -> Result<()> {
     let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
     match old_mode {
         Mode::AccumulateBlocks(mut blocks) => {
             state.mode = Mode::AccumulateListItems(Vec::new());
-            walk_children(state, node);
+            walk_children(state, node)?;
             let mode = mem::replace(&mut state.mode, Mode::Placeholder);
             match mode {
                 Mode::AccumulateListItems(items) => {
@@ -299,23 +472,24 @@ fn handle_list(state: &mut State, node: &Node, type_: doc::ListType) {
                     blocks.push(new_block);
                     state.mode = Mode::AccumulateBlocks(blocks);
                 },
-                e => panic!("unexpected mode {:?}", e),
+                e => bail!("unexpected mode {:?}", e),
             }
         }
         _ => {
-            //warn!("unhandled list");
+            diagnose_nesting(state, node, &old_mode);
             state.mode = old_mode;
-            walk_children(state, node);
+            walk_children(state, node)?;
         }
     }
+    Ok(())
 }
 
-fn handle_list_item(state: &mut State, node: &Node) {
+fn handle_list_item(state: &mut State, node: &Node) -> Result<()> {
     let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
     match old_mode {
         Mode::AccumulateListItems(mut items) => {
             state.mode = Mode::AccumulateBlocks(Vec::new());
-            walk_block_children(state, node);
+            walk_block_children(state, node)?;
             let mode = mem::replace(&mut state.mode, Mode::Placeholder);
             match mode {
                 Mode::AccumulateBlocks(blocks) => {
@@ -323,23 +497,24 @@ fn handle_list_item(state: &mut State, node: &Node) {
                     items.push(new_item);
                     state.mode = Mode::AccumulateListItems(items);
                 },
-                e => panic!("unexpected mode {:?}", e),
+                e => bail!("unexpected mode {:?}", e),
             }
         },
         _ => {
-            //warn!("unhandled list item");
+            diagnose_nesting(state, node, &old_mode);
             state.mode = old_mode;
-            walk_children(state, node);
+            walk_children(state, node)?;
         }
     }
+    Ok(())
 }
 
-fn handle_blockquote(state: &mut State, node: &Node) {
+fn handle_blockquote(state: &mut State, node: &Node) -> Result<()> {
     let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
     match old_mode {
         Mode::AccumulateBlocks(mut blocks) => {
             state.mode = Mode::AccumulateBlocks(Vec::new());
-            walk_block_children(state, node);
+            walk_block_children(state, node)?;
             let mode = mem::replace(&mut state.mode, Mode::Placeholder);
             match mode {
                 Mode::AccumulateBlocks(new_blocks) => {
@@ -348,62 +523,66 @@ fn handle_blockquote(state: &mut State, node: &Node) {
                     blocks.push(new_block);
                     state.mode = Mode::AccumulateBlocks(blocks)
                 },
-                e => panic!("unexpected mode {:?}", e),
+                e => bail!("unexpected mode {:?}", e),
             }
         }
         _ => {
-            //warn!("unhandled blockquote item")
+            diagnose_nesting(state, node, &old_mode);
             state.mode = old_mode;
-            walk_children(state, node);
+            walk_children(state, node)?;
         }
     }
+    Ok(())
 }
 
-fn handle_thematic_break(state: &mut State, _node: &Node) {
+fn handle_thematic_break(state: &mut State, _node: &Node) -> Result<()> {
     match state.mode {
         Mode::AccumulateBlocks(ref mut blocks) => {
             blocks.push(doc::Block::ThematicBreak);
         }
         _ => {
-            //warn!("unhandled thematic break")
+            diagnose(state, DiagnosticKind::DroppedElement("hr".to_string()));
         }
     }
+    Ok(())
 }
 
-fn handle_pre(state: &mut State, node: &Node) {
+fn handle_pre(state: &mut State, node: &Node) -> Result<()> {
     let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
     match old_mode {
         Mode::AccumulateBlocks(mut blocks) => {
             state.mode = Mode::AccumulateInlines(Vec::new());
-            walk_children(state, node);
+            walk_children(state, node)?;
             let mode = mem::replace(&mut state.mode, Mode::Placeholder);
             match mode {
                 Mode::AccumulateInlines(inlines) => {
+                    let lang = code_lang(node, &inlines);
                     let new_code_block = doc::CodeBlock {
-                        lang: doc::CodeLang::Unknown,
+                        lang,
                         inlines
                     };
                     let new_block = doc::Block::CodeBlock(new_code_block);
                     blocks.push(new_block);
                     state.mode = Mode::AccumulateBlocks(blocks);
                 }
-                e => panic!("unexpected mode {:?}", e),
+                e => bail!("unexpected mode {:?}", e),
             }
         }
         _ => {
-            //warn!("unhandled para");
+            diagnose_nesting(state, node, &old_mode);
             state.mode = old_mode;
-            walk_children(state, node);
+            walk_children(state, node)?;
         }
     }
+    Ok(())
 }
 
-fn handle_emph(state: &mut State, node: &Node, name: &str) {
+fn handle_emph(state: &mut State, node: &Node, name: &str) -> Result<()> {
     let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
     match old_mode {
         Mode::AccumulateInlines(mut inlines) => {
             state.mode = Mode::AccumulateInlines(Vec::new());
-            walk_children(state, node);
+            walk_children(state, node)?;
             let mode = mem::replace(&mut state.mode, Mode::Placeholder);
             match mode {
                 Mode::AccumulateInlines(new_inlines) => {
@@ -421,18 +600,429 @@ fn handle_emph(state: &mut State, node: &Node, name: &str) {
                             inlines.push(new_inline);
                         }
                         _ => {
-                            panic!("unexpected tag {}", name);
+                            bail!("unexpected tag {}", name);
                         }
                     }
                     state.mode = Mode::AccumulateInlines(inlines);
                 }
-                _ => panic!("unexpected mode {:?}", mode),
+                _ => bail!("unexpected mode {:?}", mode),
             }
         }
         _ => {
-            //warn!("unhandled emph");
+            diagnose(state, DiagnosticKind::DroppedElement(name.to_string()));
             state.mode = old_mode;
-            walk_children(state, node);
+            walk_children(state, node)?;
         }
     }
+    Ok(())
+}
+
+fn handle_link(state: &mut State, node: &Node, href: Option<String>, title: Option<String>) -> Result<()> {
+    let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
+    match old_mode {
+        Mode::AccumulateInlines(mut inlines) => {
+            state.mode = Mode::AccumulateInlines(Vec::new());
+            walk_children(state, node)?;
+            let mode = mem::replace(&mut state.mode, Mode::Placeholder);
+            match mode {
+                Mode::AccumulateInlines(new_inlines) => {
+                    match href {
+                        Some(href) => {
+                            let new_inline = doc::Inline::Link(doc::Link {
+                                href: resolve_url(&state.base_url, &href),
+                                title,
+                                inlines: new_inlines,
+                            });
+                            inlines.push(new_inline);
+                        }
+                        None => {
+                            // A named anchor, not a link
+                            inlines.extend(new_inlines);
+                        }
+                    }
+                    state.mode = Mode::AccumulateInlines(inlines);
+                }
+                _ => bail!("unexpected mode {:?}", mode),
+            }
+        }
+        _ => {
+            diagnose(state, DiagnosticKind::DroppedElement("a".to_string()));
+            state.mode = old_mode;
+            walk_children(state, node)?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a possibly-relative URL from the source page so that it still
+/// points to the right place once the post is rehosted.
+fn resolve_url(base_url: &Url, href: &str) -> String {
+    match base_url.join(href.trim()) {
+        Ok(url) => url.to_string(),
+        Err(e) => {
+            debug!("unable to resolve url {:?}: {}", href, e);
+            href.to_string()
+        }
+    }
+}
+
+fn handle_image(state: &mut State, image: Option<doc::Image>) -> Result<()> {
+    let image = match image {
+        Some(image) => image,
+        None => {
+            diagnose(state, DiagnosticKind::DroppedElement("img".to_string()));
+            return Ok(());
+        }
+    };
+    match state.mode {
+        Mode::AccumulateBlocks(ref mut blocks) => {
+            blocks.push(doc::Block::Image(image));
+        }
+        Mode::AccumulateInlines(ref mut inlines) => {
+            inlines.push(doc::Inline::Image(image));
+        }
+        _ => {
+            diagnose(state, DiagnosticKind::DroppedElement("img".to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Figures are only recognized if they contain an image. Other figures, e.g.
+/// those wrapping code listings, are walked like any other container.
+fn handle_figure(state: &mut State, node: &Node) -> Result<()> {
+    let image = find_image(state, node);
+    let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
+    match (old_mode, image) {
+        (Mode::AccumulateBlocks(mut blocks), Some(image)) => {
+            let caption = match find_descendant(node, "figcaption") {
+                Some(figcaption) => collect_inlines(state, &figcaption)?,
+                None => Vec::new(),
+            };
+            let new_figure = doc::Figure { image, caption };
+            blocks.push(doc::Block::Figure(new_figure));
+            state.mode = Mode::AccumulateBlocks(blocks);
+        }
+        (old_mode, _) => {
+            state.mode = old_mode;
+            walk_children(state, node)?;
+        }
+    }
+    Ok(())
+}
+
+/// Finds the image inside a `picture` or `figure`, preferring the `img`
+/// fallback and then the first `source`.
+fn find_image(state: &State, node: &Node) -> Option<doc::Image> {
+    let img = find_descendant(node, "img");
+    let image = img.and_then(|img| match &img.data {
+        NodeData::Element { attrs, .. } => image_from_attrs(state, attrs),
+        _ => None,
+    });
+    if image.is_some() {
+        return image;
+    }
+
+    let source = find_descendant(node, "source");
+    source.and_then(|source| match &source.data {
+        NodeData::Element { attrs, .. } => {
+            let src = html::find_attr(attrs, "srcset")
+                .and_then(|srcset| first_srcset_url(&srcset))?;
+            Some(doc::Image {
+                src: resolve_url(&state.base_url, &src),
+                alt: None,
+                title: None,
+            })
+        }
+        _ => None,
+    })
+}
+
+/// Lazy-loading scripts often leave `src` empty or pointing at an inline
+/// placeholder, with the real location in `data-src` or `srcset`.
+fn image_from_attrs(state: &State, attrs: &RefCell<Vec<Attribute>>) -> Option<doc::Image> {
+    let usable = |src: &String| {
+        let src = src.trim();
+        !src.is_empty() && !src.starts_with("data:")
+    };
+    let src = html::find_attr(attrs, "src").filter(usable)
+        .or_else(|| html::find_attr(attrs, "data-src").filter(usable))
+        .or_else(|| {
+            html::find_attr(attrs, "srcset")
+                .and_then(|srcset| first_srcset_url(&srcset))
+        })?;
+
+    Some(doc::Image {
+        src: resolve_url(&state.base_url, &src),
+        alt: html::find_attr(attrs, "alt"),
+        title: html::find_attr(attrs, "title"),
+    })
+}
+
+fn first_srcset_url(srcset: &str) -> Option<String> {
+    srcset.split(',')
+        .filter_map(|candidate| candidate.split_whitespace().next())
+        .map(String::from)
+        .next()
+}
+
+fn find_descendant(node: &Node, name: &str) -> Option<Handle> {
+    for child in node.children.borrow().iter() {
+        if let NodeData::Element { name: child_name, .. } = &child.data {
+            if child_name.local.as_ref() == name {
+                return Some(child.clone());
+            }
+        }
+        if let Some(found) = find_descendant(child, name) {
+            return Some(found);
+        }
+    }
+
+    None
+}
+
+fn handle_table(state: &mut State, node: &Node) -> Result<()> {
+    let old_mode = mem::replace(&mut state.mode, Mode::Placeholder);
+    match old_mode {
+        Mode::AccumulateBlocks(mut blocks) => {
+            let mut table = doc::Table {
+                caption: Vec::new(),
+                alignments: Vec::new(),
+                header: Vec::new(),
+                body: Vec::new(),
+            };
+            walk_table(state, node, &mut table, false)?;
+            blocks.push(doc::Block::Table(table));
+            state.mode = Mode::AccumulateBlocks(blocks);
+        }
+        _ => {
+            diagnose_nesting(state, node, &old_mode);
+            state.mode = old_mode;
+            walk_children(state, node)?;
+        }
+    }
+    Ok(())
+}
+
+/// Collects the rows of a table, descending through row groups. Rows in a
+/// `thead`, or leading rows made entirely of `th` cells, become header rows.
+fn walk_table(state: &mut State, node: &Node, table: &mut doc::Table, in_head: bool) -> Result<()> {
+    for child in node.children.borrow().iter() {
+        let name = match &child.data {
+            NodeData::Element { name, .. } => name.local.as_ref(),
+            _ => continue,
+        };
+        state.path.push(path_segment(child));
+        match name {
+            "caption" => {
+                table.caption = collect_inlines(state, child)?;
+            }
+            "thead" => {
+                walk_table(state, child, table, true)?;
+            }
+            "tbody" | "tfoot" => {
+                walk_table(state, child, table, false)?;
+            }
+            "tr" => {
+                let (row, all_th) = table_row(state, child, &mut table.alignments)?;
+                if in_head || (all_th && table.body.is_empty()) {
+                    table.header.push(row);
+                } else {
+                    table.body.push(row);
+                }
+            }
+            "colgroup" | "col" => { }
+            _ => {
+                diagnose(state, DiagnosticKind::DroppedElement(name.to_string()));
+            }
+        }
+        state.path.pop();
+    }
+    Ok(())
+}
+
+fn table_row(state: &mut State, node: &Node, alignments: &mut Vec<doc::Alignment>) -> Result<(doc::TableRow, bool)> {
+    let mut cells = Vec::new();
+    let mut all_th = true;
+
+    for child in node.children.borrow().iter() {
+        let (name, attrs) = match &child.data {
+            NodeData::Element { name, attrs, .. } => (name.local.as_ref(), attrs),
+            _ => continue,
+        };
+        state.path.push(path_segment(child));
+        match name {
+            "th" | "td" => {
+                all_th &= name == "th";
+                let column = cells.len();
+                if alignments.len() <= column {
+                    alignments.resize(column + 1, doc::Alignment::Default);
+                }
+                if alignments[column] == doc::Alignment::Default {
+                    alignments[column] = cell_alignment(attrs);
+                }
+                let inlines = collect_inlines(state, child)?;
+                cells.push(doc::TableCell { inlines });
+            }
+            _ => {
+                diagnose(state, DiagnosticKind::DroppedElement(name.to_string()));
+            }
+        }
+        state.path.pop();
+    }
+
+    let all_th = all_th && !cells.is_empty();
+    Ok((doc::TableRow { cells }, all_th))
+}
+
+/// Reads the alignment from either the obsolete `align` attribute or an
+/// inline `text-align` style, which is what markdown renderers emit.
+fn cell_alignment(attrs: &RefCell<Vec<Attribute>>) -> doc::Alignment {
+    let style = html::find_attr(attrs, "style").map(|style| {
+        style.split(';')
+            .filter_map(|decl| {
+                let mut parts = decl.splitn(2, ':');
+                let prop = parts.next()?.trim();
+                let value = parts.next()?.trim();
+                if prop.eq_ignore_ascii_case("text-align") {
+                    Some(value.to_string())
+                } else {
+                    None
+                }
+            })
+            .next()
+    });
+    let align = html::find_attr(attrs, "align")
+        .or(style.flatten())
+        .map(|align| align.to_lowercase());
+
+    match align.as_ref().map(String::as_str) {
+        Some("left") => doc::Alignment::Left,
+        Some("center") => doc::Alignment::Center,
+        Some("right") => doc::Alignment::Right,
+        _ => doc::Alignment::Default,
+    }
+}
+
+fn collect_inlines(state: &mut State, node: &Node) -> Result<Vec<doc::Inline>> {
+    let old_mode = mem::replace(&mut state.mode, Mode::AccumulateInlines(Vec::new()));
+    walk_children(state, node)?;
+    let mode = mem::replace(&mut state.mode, old_mode);
+    match mode {
+        Mode::AccumulateInlines(inlines) => Ok(inlines),
+        e => bail!("unexpected mode {:?}", e),
+    }
+}
+
+/// Determines the language of a `pre` block, first from the labels that
+/// highlighters leave on the `pre`, its `code` child, and wrapping divs, then
+/// by looking at the code itself.
+fn code_lang(pre: &Node, inlines: &Vec<doc::Inline>) -> doc::CodeLang {
+    if let Some(lang) = labeled_code_lang(pre) {
+        return lang;
+    }
+
+    for child in pre.children.borrow().iter() {
+        if let Some(lang) = labeled_code_lang(child) {
+            return lang;
+        }
+    }
+
+    let mut ancestor = parent(pre);
+    for _ in 0..3 {
+        match ancestor {
+            Some(node) => {
+                let is_div = match &node.data {
+                    NodeData::Element { name, .. } => name.local.as_ref() == "div",
+                    _ => false,
+                };
+                if !is_div {
+                    break;
+                }
+                if let Some(lang) = labeled_code_lang(&node) {
+                    return lang;
+                }
+                ancestor = parent(&node);
+            }
+            None => break,
+        }
+    }
+
+    guess_code_lang(&extract::inlines_text(inlines))
+}
+
+/// Recognizes `data-lang` attributes and the class conventions of the common
+/// highlighters, e.g. `language-rust`, `highlight-rust`, `sourceCode rust`,
+/// and SyntaxHighlighter's `brush: rust;`.
+fn labeled_code_lang(node: &Node) -> Option<doc::CodeLang> {
+    let attrs = match &node.data {
+        NodeData::Element { attrs, .. } => attrs,
+        _ => return None,
+    };
+
+    let data_lang = html::find_attr(attrs, "data-lang")
+        .or_else(|| html::find_attr(attrs, "data-language"));
+    if let Some(lang) = data_lang.and_then(|l| doc::CodeLang::from_name(l.trim())) {
+        return Some(lang);
+    }
+
+    let class = html::find_attr(attrs, "class")?.replace(';', " ");
+    let prefixes = ["language-", "lang-", "highlight-source-", "highlight-", "source-"];
+    for token in class.split_whitespace() {
+        // Fences like `rust,ignore`
+        let token = token.split(',').next().unwrap_or(token);
+        let name = prefixes.iter()
+            .filter_map(|prefix| token.strip_prefix(prefix))
+            .next()
+            .unwrap_or(token);
+        if let Some(lang) = doc::CodeLang::from_name(name) {
+            return Some(lang);
+        }
+    }
+
+    None
+}
+
+fn guess_code_lang(code: &str) -> doc::CodeLang {
+    let first_line = code.lines().find(|line| !line.trim().is_empty());
+    if let Some(line) = first_line {
+        let line = line.trim_start();
+        if line.starts_with("$ ") {
+            return doc::CodeLang::Console;
+        }
+        if line == "[package]" || line == "[dependencies]" {
+            return doc::CodeLang::Toml;
+        }
+    }
+
+    lazy_static! {
+        static ref STRONG: RegexSet = RegexSet::new([
+            r"\bfn\s+\w+\s*(<[^>]*>)?\s*\(",
+            r"\bimpl\b(\s*<[^>]*>)?\s+[\w:]+",
+            r"\blet\s+mut\s+\w+",
+            r"\buse\s+\w+::",
+            r"#!?\[(derive|cfg|allow|test)\b",
+            r"\bpub(\(crate\))?\s+(fn|struct|enum|trait|mod)\b",
+        ]).expect("code lang regex");
+        static ref WEAK: RegexSet = RegexSet::new([
+            r"\blet\s+\w+\s*(:[^=]+)?=",
+            r"\b(println|vec|format|assert_eq|panic)!",
+            r"&mut\s",
+            r"\w::\w",
+            r"\bSome\(|\bOk\(",
+        ]).expect("code lang regex");
+    }
+
+    if STRONG.is_match(code) || WEAK.matches(code).iter().count() > 1 {
+        doc::CodeLang::Rust
+    } else {
+        doc::CodeLang::Unknown
+    }
+}
+
+fn parent(node: &Node) -> Option<Handle> {
+    let weak = node.parent.take();
+    let parent = weak.as_ref().and_then(|weak| weak.upgrade());
+    node.parent.set(weak);
+    parent
 }
@@ -0,0 +1,234 @@
+//! Conversion between seconds since the Unix epoch and UTC dates
+
+/// A UTC date and time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn from_secs(secs: u64) -> DateTime {
+        let days = (secs / 86400) as i64;
+        let secs_of_day = secs % 86400;
+
+        // Howard Hinnant's `civil_from_days`
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year,
+            month: month as u32,
+            day: day as u32,
+            hour: (secs_of_day / 3600) as u32,
+            minute: (secs_of_day % 3600 / 60) as u32,
+            second: (secs_of_day % 60) as u32,
+        }
+    }
+
+    /// Seconds since the Unix epoch, or `None` for dates before it
+    pub fn to_secs(self) -> Option<u64> {
+        // Howard Hinnant's `days_from_civil`
+        let year = if self.month <= 2 { self.year - 1 } else { self.year };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = self.month as i64;
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        let secs = days * 86400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+        if secs < 0 {
+            None
+        } else {
+            Some(secs as u64)
+        }
+    }
+
+    /// Parses an ISO 8601 date, like `2020-05-03T12:30:00-07:00`, converting
+    /// it to UTC. The time is optional, as are seconds, fractional seconds
+    /// and the offset, and anything after them is ignored.
+    pub fn parse_iso8601(s: &str) -> Option<DateTime> {
+        let s = s.trim();
+        let bytes = s.as_bytes();
+        let num = |range: std::ops::Range<usize>| -> Option<u32> {
+            let part = s.get(range)?;
+            if part.bytes().all(|b| b.is_ascii_digit()) {
+                part.parse().ok()
+            } else {
+                None
+            }
+        };
+        let at = |i: usize, expected: &[u8]| bytes.get(i).map(|b| expected.contains(b)).unwrap_or(false);
+
+        if !at(4, b"-") || !at(7, b"-") {
+            return None;
+        }
+        let mut date = DateTime {
+            year: num(0..4)? as i64,
+            month: num(5..7)?,
+            day: num(8..10)?,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        };
+        if date.month < 1 || date.month > 12 || date.day < 1 || date.day > 31 {
+            return None;
+        }
+        if !at(10, b"Tt ") || !at(11, b"0123456789") {
+            return Some(date);
+        }
+
+        if !at(13, b":") {
+            return None;
+        }
+        date.hour = num(11..13)?;
+        date.minute = num(14..16)?;
+        let mut pos = 16;
+        if at(16, b":") {
+            date.second = num(17..19)?;
+            pos = 19;
+            if at(19, b".,") {
+                pos = 20;
+                while at(pos, b"0123456789") {
+                    pos += 1;
+                }
+            }
+        }
+        // 60 is a leap second
+        if date.hour > 23 || date.minute > 59 || date.second > 60 {
+            return None;
+        }
+
+        let offset = match bytes.get(pos) {
+            Some(b'+') | Some(b'-') => {
+                let hours = num(pos + 1..pos + 3)?;
+                let minutes = if at(pos + 3, b":") {
+                    num(pos + 4..pos + 6)?
+                } else {
+                    num(pos + 3..pos + 5).unwrap_or(0)
+                };
+                if hours > 23 || minutes > 59 {
+                    return None;
+                }
+                let offset = (hours * 3600 + minutes * 60) as i64;
+                if bytes[pos] == b'-' { -offset } else { offset }
+            }
+            _ => 0,
+        };
+        if offset != 0 {
+            // Dates before the epoch are left as they are
+            if let Some(secs) = date.to_secs() {
+                let utc = secs as i64 - offset;
+                if utc >= 0 {
+                    date = DateTime::from_secs(utc as u64);
+                }
+            }
+        }
+        Some(date)
+    }
+
+    /// Parses an HTTP date, like `Sun, 03 May 2020 12:30:00 GMT`
+    pub fn parse_http_date(s: &str) -> Option<DateTime> {
+        static MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun",
+            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let mut parts = s.split_whitespace().skip(1);
+        let day = parts.next()?.parse().ok()?;
+        let month = parts.next()?;
+        let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u32 + 1;
+        let year = parts.next()?.parse().ok()?;
+        let mut time = parts.next()?.split(':').map(|part| part.parse().ok());
+        let date = DateTime {
+            year, month, day,
+            hour: time.next()??,
+            minute: time.next()??,
+            second: time.next()??,
+        };
+        if date.day < 1 || date.day > 31 {
+            return None;
+        }
+        Some(date)
+    }
+
+    /// Formats the day, without the time, as ISO 8601, e.g. `2020-05-03`
+    pub fn iso8601_date(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    /// Formats the date as ISO 8601, e.g. `2020-05-03T12:30:00Z`
+    pub fn iso8601(&self) -> String {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                self.year, self.month, self.day,
+                self.hour, self.minute, self.second)
+    }
+}
+
+/// Formats seconds since the Unix epoch as a short UTC date and time
+pub fn format_date(secs: u64) -> String {
+    let date = DateTime::from_secs(secs);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}",
+            date.year, date.month, date.day, date.hour, date.minute)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iso(s: &str) -> Option<String> {
+        DateTime::parse_iso8601(s).map(|date| date.iso8601())
+    }
+
+    #[test]
+    fn parse_iso8601_dates() {
+        assert_eq!(iso("2020-05-03"), Some("2020-05-03T00:00:00Z".to_string()));
+        assert_eq!(iso(" 2020-05-03 "), Some("2020-05-03T00:00:00Z".to_string()));
+        assert_eq!(iso("2020-05-03T12:30"), Some("2020-05-03T12:30:00Z".to_string()));
+        assert_eq!(iso("2020-05-03 12:30:15"), Some("2020-05-03T12:30:15Z".to_string()));
+        assert_eq!(iso("2020-05-03T12:30:15.123Z"), Some("2020-05-03T12:30:15Z".to_string()));
+    }
+
+    #[test]
+    fn parse_iso8601_offsets() {
+        assert_eq!(iso("2020-05-03T23:30:00-07:00"), Some("2020-05-04T06:30:00Z".to_string()));
+        assert_eq!(iso("2020-05-03T01:30:00+02:00"), Some("2020-05-02T23:30:00Z".to_string()));
+        assert_eq!(iso("2020-05-03T12:30:00.5+0530"), Some("2020-05-03T07:00:00Z".to_string()));
+        assert_eq!(iso("2020-05-03T12:30:00+00:00"), Some("2020-05-03T12:30:00Z".to_string()));
+    }
+
+    #[test]
+    fn parse_iso8601_rejects() {
+        assert_eq!(iso("2020x05y03"), None);
+        assert_eq!(iso("2020-13-03"), None);
+        assert_eq!(iso("2020-05-00"), None);
+        assert_eq!(iso("2020-05-03T24:00"), None);
+        assert_eq!(iso("2020-05-03T12:60"), None);
+        assert_eq!(iso("2020-05-03T12-30"), None);
+        assert_eq!(iso("2020-05-03T12:30:00+25:00"), None);
+        assert_eq!(iso("May 3, 2020"), None);
+        assert_eq!(iso(""), None);
+    }
+
+    #[test]
+    fn secs_round_trip() {
+        let date = DateTime::parse_iso8601("2020-05-03T12:30:15Z").unwrap();
+        assert_eq!(date.to_secs(), Some(1588509015));
+        assert_eq!(DateTime::from_secs(1588509015), date);
+    }
+}
@@ -1,4 +1,5 @@
 use url::Url;
+use crate::date::DateTime;
 
 #[derive(Debug)]
 pub struct Document {
@@ -9,6 +10,26 @@ pub struct Document {
 #[derive(Debug)]
 pub struct Meta {
     pub origin_url: Url,
+    /// Set when the content came from an archived copy, because the origin
+    /// couldn't be fetched
+    pub snapshot: Option<Snapshot>,
+    pub page: PageMeta,
+}
+
+/// What the page says about the post in its metadata
+#[derive(Debug, Clone, Default)]
+pub struct PageMeta {
+    pub published: Option<DateTime>,
+    pub updated: Option<DateTime>,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub url: Url,
+    pub captured_at: Option<DateTime>,
 }
 
 #[derive(Debug)]
@@ -24,6 +45,9 @@ pub enum Block {
     Blockquote(Blockquote),
     ThematicBreak,
     CodeBlock(CodeBlock),
+    Image(Image),
+    Figure(Figure),
+    Table(Table),
 }
 
 #[derive(Debug)]
@@ -48,6 +72,51 @@ pub enum Inline {
     Bold(Vec<Inline>),
     Italic(Vec<Inline>),
     Code(Vec<Inline>),
+    Link(Link),
+    Image(Image),
+}
+
+#[derive(Debug)]
+pub struct Link {
+    pub href: String,
+    pub title: Option<String>,
+    pub inlines: Vec<Inline>,
+}
+
+#[derive(Debug)]
+pub struct Image {
+    pub src: String,
+    pub alt: Option<String>,
+    pub title: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Figure {
+    pub image: Image,
+    pub caption: Vec<Inline>,
+}
+
+#[derive(Debug)]
+pub struct Table {
+    pub caption: Vec<Inline>,
+    pub alignments: Vec<Alignment>,
+    pub header: Vec<TableRow>,
+    pub body: Vec<TableRow>,
+}
+
+#[derive(Debug)]
+pub struct TableRow {
+    pub cells: Vec<TableCell>,
+}
+
+#[derive(Debug)]
+pub struct TableCell {
+    pub inlines: Vec<Inline>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Default, Left, Center, Right,
 }
 
 #[derive(Debug)]
@@ -77,8 +146,66 @@ pub struct CodeBlock {
     pub inlines: Vec<Inline>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CodeLang {
     Rust,
+    Toml,
+    Shell,
+    /// A shell session: prompts, commands and their output
+    Console,
+    C,
+    Cpp,
+    Json,
+    Yaml,
+    Python,
+    JavaScript,
+    Html,
+    Diff,
+    Text,
     Unknown,
 }
+
+impl CodeLang {
+    /// Maps the names and aliases used by highlighters and markdown fences
+    pub fn from_name(name: &str) -> Option<CodeLang> {
+        use CodeLang::*;
+        let lang = match name.to_lowercase().as_str() {
+            "rust" | "rs" => Rust,
+            "toml" => Toml,
+            "sh" | "bash" | "shell" | "zsh" | "fish" => Shell,
+            "console" | "shell-session" | "shellsession" | "sh-session" | "terminal" => Console,
+            "c" | "h" => C,
+            "cpp" | "c++" | "cxx" | "cc" | "hpp" => Cpp,
+            "json" => Json,
+            "yaml" | "yml" => Yaml,
+            "python" | "py" | "python3" => Python,
+            "javascript" | "js" => JavaScript,
+            "html" | "xhtml" | "xml" => Html,
+            "diff" | "patch" => Diff,
+            "text" | "txt" | "plain" | "plaintext" | "output" | "none" => Text,
+            _ => return None,
+        };
+        Some(lang)
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        use CodeLang::*;
+        let name = match self {
+            Rust => "rust",
+            Toml => "toml",
+            Shell => "shell",
+            Console => "console",
+            C => "c",
+            Cpp => "cpp",
+            Json => "json",
+            Yaml => "yaml",
+            Python => "python",
+            JavaScript => "javascript",
+            Html => "html",
+            Diff => "diff",
+            Text => "text",
+            Unknown => return None,
+        };
+        Some(name)
+    }
+}
@@ -15,6 +15,47 @@ pub fn title(doc: &Document) -> Option<String> {
     headings.pop().map(|h| h.1)
 }
 
+/// All the text in the document, with blocks separated by newlines
+pub fn text(doc: &Document) -> String {
+    let mut buf = String::new();
+    cat_text_blocks(&mut buf, &doc.body.blocks);
+    buf
+}
+
+fn cat_text_blocks(buf: &mut String, blocks: &Vec<Block>) {
+    for block in blocks {
+        match block {
+            Block::Heading(Heading { inlines, .. }) |
+            Block::Paragraph(Paragraph { inlines }) |
+            Block::CodeBlock(CodeBlock { inlines, .. }) => {
+                cat_text_inlines(buf, inlines);
+            }
+            Block::List(list) => {
+                for item in &list.items {
+                    cat_text_blocks(buf, &item.blocks);
+                }
+            }
+            Block::Blockquote(Blockquote { blocks }) => {
+                cat_text_blocks(buf, blocks);
+            }
+            Block::Figure(figure) => {
+                cat_text_inlines(buf, &figure.caption);
+            }
+            Block::Table(table) => {
+                cat_text_inlines(buf, &table.caption);
+                for row in table.header.iter().chain(table.body.iter()) {
+                    for cell in &row.cells {
+                        cat_text_inlines(buf, &cell.inlines);
+                        buf.push(' ');
+                    }
+                }
+            }
+            Block::Image(_) | Block::ThematicBreak => { }
+        }
+        buf.push('\n');
+    }
+}
+
 type Hd = (HeadingLevel, String);
 
 fn walk_doc(hs: &mut Vec<Hd>, doc: &Document) {
@@ -34,6 +75,12 @@ fn walk_blocks(hs: &mut Vec<Hd>, blocks: &Vec<Block>) {
     }
 }
 
+pub fn inlines_text(inlines: &Vec<Inline>) -> String {
+    let mut buf = String::new();
+    cat_text_inlines(&mut buf, inlines);
+    buf
+}
+
 fn cat_text_inlines(buf: &mut String, inlines: &Vec<Inline>) {
     for inline in inlines {
         match inline {
@@ -45,6 +92,10 @@ fn cat_text_inlines(buf: &mut String, inlines: &Vec<Inline>) {
             Inline::Code(inlines) => {
                 cat_text_inlines(buf, inlines);
             }
+            Inline::Link(link) => {
+                cat_text_inlines(buf, &link.inlines);
+            }
+            Inline::Image(_) => { }
         }
     }
 }
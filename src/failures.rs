@@ -0,0 +1,145 @@
+//! A ledger of URLs that failed to fetch, kept in the cache directory so that
+//! known-dead URLs aren't requested again on every run. Only permanent
+//! failures make a URL dead; others are recorded, but retried next time.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, Context};
+use url::Url;
+use crate::http_cache;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    /// The server responded with an error status
+    Status,
+    Dns,
+    Connect,
+    Timeout,
+    /// Not fetched, because robots.txt disallows it
+    Robots,
+    Other,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lbl = match self {
+            FailureKind::Status => "status",
+            FailureKind::Dns => "dns",
+            FailureKind::Connect => "connect",
+            FailureKind::Timeout => "timeout",
+            FailureKind::Robots => "robots",
+            FailureKind::Other => "other",
+        };
+        write!(f, "{}", lbl)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchFailure {
+    pub kind: FailureKind,
+    pub status: Option<u16>,
+    pub error: String,
+    /// Seconds since the Unix epoch
+    pub first_failed: u64,
+    pub last_failed: u64,
+    pub attempts: u32,
+}
+
+impl FetchFailure {
+    /// Whether the URL is gone for good, rather than having hit a network
+    /// problem or a server error that may pass. Robots.txt disallowing it
+    /// counts, as it's not fetched again until robots.txt is ignored.
+    pub fn is_permanent(&self) -> bool {
+        match self.kind {
+            FailureKind::Dns | FailureKind::Robots => true,
+            FailureKind::Status => self.status == Some(404) || self.status == Some(410),
+            FailureKind::Connect | FailureKind::Timeout | FailureKind::Other => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct LedgerFile {
+    #[serde(default)]
+    failures: BTreeMap<String, FetchFailure>,
+}
+
+/// The failure ledger. Every change is written through to disk.
+pub struct FailureLedger {
+    path: PathBuf,
+    failures: Mutex<BTreeMap<String, FetchFailure>>,
+}
+
+impl FailureLedger {
+    pub fn load(path: PathBuf) -> Result<FailureLedger> {
+        let file = match fs::read_to_string(&path) {
+            Ok(file) => {
+                toml::from_str(&file)
+                    .context("parsing failure ledger")?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => LedgerFile::default(),
+            Err(e) => return Err(e).context("reading failure ledger"),
+        };
+        Ok(FailureLedger {
+            path,
+            failures: Mutex::new(file.failures),
+        })
+    }
+
+    pub fn get(&self, url: &Url) -> Option<FetchFailure> {
+        let failures = self.failures.lock().expect("poisoned");
+        failures.get(url.as_str()).cloned()
+    }
+
+    /// All failures, by URL
+    pub fn failures(&self) -> BTreeMap<String, FetchFailure> {
+        self.failures.lock().expect("poisoned").clone()
+    }
+
+    pub fn record(&self, url: &Url, kind: FailureKind, status: Option<u16>, error: String, now: u64) -> Result<()> {
+        let mut failures = self.failures.lock().expect("poisoned");
+        let failure = failures.entry(url.as_str().to_string())
+            .or_insert(FetchFailure {
+                kind, status,
+                error: String::new(),
+                first_failed: now,
+                last_failed: now,
+                attempts: 0,
+            });
+        failure.kind = kind;
+        failure.status = status;
+        failure.error = error;
+        failure.last_failed = now;
+        failure.attempts += 1;
+        self.save(&failures)
+    }
+
+    /// Forgets a URL's failures, after it has been fetched successfully
+    pub fn clear(&self, url: &Url) -> Result<()> {
+        let mut failures = self.failures.lock().expect("poisoned");
+        if failures.remove(url.as_str()).is_some() {
+            self.save(&failures)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, failures: &BTreeMap<String, FetchFailure>) -> Result<()> {
+        let file = LedgerFile {
+            failures: failures.clone(),
+        };
+        let file = toml::to_string(&file)
+            .context("serializing failure ledger")?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .context("creating cache dir")?;
+        }
+        http_cache::write_atomic(&self.path, file)
+            .context("writing failure ledger")
+    }
+}
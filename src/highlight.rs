@@ -0,0 +1,252 @@
+//! A tiny Rust lexer for syntax highlighting rendered code blocks. It doesn't
+//! need to understand Rust, just to find the boundaries of the tokens worth
+//! coloring, and it must never lose text, even on malformed input.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Keyword,
+    Lifetime,
+    Macro,
+    String,
+    Comment,
+    Attribute,
+    Number,
+}
+
+impl Class {
+    pub fn css_class(&self) -> &'static str {
+        match self {
+            Class::Keyword => "kw",
+            Class::Lifetime => "lifetime",
+            Class::Macro => "macro",
+            Class::String => "string",
+            Class::Comment => "comment",
+            Class::Attribute => "attr",
+            Class::Number => "number",
+        }
+    }
+}
+
+/// A run of source text, with the class it should be highlighted as, if any
+pub type Span<'a> = (Option<Class>, &'a str);
+
+static KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn",
+    "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let",
+    "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self",
+    "Self", "static", "struct", "super", "trait", "true", "type", "unsafe",
+    "use", "where", "while",
+];
+
+pub fn rust(src: &str) -> Vec<Span<'_>> {
+    let mut spans = Vec::new();
+    let mut plain_start = 0;
+    let mut pos = 0;
+
+    while pos < src.len() {
+        let rest = &src[pos..];
+        let (class, len) = next_token(rest);
+        match class {
+            Some(class) => {
+                if plain_start < pos {
+                    spans.push((None, &src[plain_start..pos]));
+                }
+                spans.push((Some(class), &rest[..len]));
+                pos += len;
+                plain_start = pos;
+            }
+            None => {
+                pos += len;
+            }
+        }
+    }
+
+    if plain_start < src.len() {
+        spans.push((None, &src[plain_start..]));
+    }
+
+    spans
+}
+
+/// Returns the class and byte length of the token at the start of `rest`.
+/// Unclassified tokens are still consumed whole so that e.g. keywords aren't
+/// found inside longer identifiers.
+fn next_token(rest: &str) -> (Option<Class>, usize) {
+    let c = rest.chars().next().expect("empty input");
+
+    if rest.starts_with("//") {
+        let len = rest.find('\n').unwrap_or(rest.len());
+        return (Some(Class::Comment), len);
+    }
+    if rest.starts_with("/*") {
+        return (Some(Class::Comment), block_comment_len(rest));
+    }
+    if rest.starts_with("#[") || rest.starts_with("#![") {
+        return (Some(Class::Attribute), attribute_len(rest));
+    }
+    if let Some(len) = string_len(rest) {
+        return (Some(Class::String), len);
+    }
+    if c == '\'' {
+        return quote_token(rest);
+    }
+    if c.is_ascii_digit() {
+        return (Some(Class::Number), number_len(rest));
+    }
+    if is_ident_start(c) {
+        let len = ident_len(rest);
+        let ident = &rest[..len];
+        let after = &rest[len..];
+        if after.starts_with('!') && !after.starts_with("!=") {
+            return (Some(Class::Macro), len + 1);
+        }
+        if KEYWORDS.contains(&ident) {
+            return (Some(Class::Keyword), len);
+        }
+        return (None, len);
+    }
+
+    (None, c.len_utf8())
+}
+
+fn is_ident_start(c: char) -> bool {
+    c == '_' || c.is_alphabetic()
+}
+
+fn is_ident_continue(c: char) -> bool {
+    c == '_' || c.is_alphanumeric()
+}
+
+fn ident_len(s: &str) -> usize {
+    s.char_indices()
+        .find(|&(_, c)| !is_ident_continue(c))
+        .map(|(i, _)| i)
+        .unwrap_or(s.len())
+}
+
+fn block_comment_len(s: &str) -> usize {
+    let mut depth = 0;
+    let mut pos = 0;
+    while pos < s.len() {
+        let rest = &s[pos..];
+        if rest.starts_with("/*") {
+            depth += 1;
+            pos += 2;
+        } else if rest.starts_with("*/") {
+            depth -= 1;
+            pos += 2;
+            if depth == 0 {
+                return pos;
+            }
+        } else {
+            pos += rest.chars().next().map(char::len_utf8).unwrap_or(1);
+        }
+    }
+    s.len()
+}
+
+fn attribute_len(s: &str) -> usize {
+    let mut depth = 0;
+    let mut pos = 0;
+    while pos < s.len() {
+        let rest = &s[pos..];
+        if let Some(len) = string_len(rest) {
+            pos += len;
+            continue;
+        }
+        let c = rest.chars().next().expect("non-empty");
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return pos + 1;
+                }
+            }
+            '\n' => return pos,
+            _ => { }
+        }
+        pos += c.len_utf8();
+    }
+    s.len()
+}
+
+/// Recognizes string and byte string literals, raw or not, and byte
+/// literals.
+fn string_len(s: &str) -> Option<usize> {
+    let after_b = s.strip_prefix('b').unwrap_or(s);
+    let prefix_len = s.len() - after_b.len();
+
+    if after_b.starts_with('"') {
+        return Some(prefix_len + quoted_len(after_b, '"'));
+    }
+    if prefix_len == 1 && after_b.starts_with('\'') {
+        return Some(prefix_len + quoted_len(after_b, '\''));
+    }
+
+    let after_r = after_b.strip_prefix('r')?;
+    let hashes = after_r.len() - after_r.trim_start_matches('#').len();
+    let body = &after_r[hashes..];
+    if !body.starts_with('"') {
+        return None;
+    }
+    let terminator = format!("\"{}", "#".repeat(hashes));
+    let start = prefix_len + 1 + hashes + 1;
+    let len = match s[start..].find(&terminator) {
+        Some(end) => start + end + terminator.len(),
+        None => s.len(),
+    };
+    Some(len)
+}
+
+/// The length of a literal delimited by `quote`, honoring backslash escapes
+fn quoted_len(s: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return i + 1;
+        }
+    }
+    s.len()
+}
+
+/// Distinguishes char literals from lifetimes and labels
+fn quote_token(s: &str) -> (Option<Class>, usize) {
+    let mut chars = s.chars().skip(1);
+    let first = chars.next();
+    let second = chars.next();
+    match (first, second) {
+        (Some('\\'), _) => {
+            (Some(Class::String), quoted_len(s, '\''))
+        }
+        (Some(c), Some('\'')) => {
+            (Some(Class::String), 1 + c.len_utf8() + 1)
+        }
+        (Some(c), _) if is_ident_start(c) => {
+            (Some(Class::Lifetime), 1 + ident_len(&s[1..]))
+        }
+        _ => (None, 1),
+    }
+}
+
+/// Numbers, including suffixes, underscores, and hex digits, but not the
+/// dots of a range expression
+fn number_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let b = bytes[pos];
+        let is_fraction = b == b'.'
+            && bytes.get(pos + 1).map(u8::is_ascii_digit).unwrap_or(false);
+        if b.is_ascii_alphanumeric() || b == b'_' || is_fraction {
+            pos += 1;
+        } else {
+            break;
+        }
+    }
+    pos
+}
@@ -1,4 +1,5 @@
 use std::iter;
+use std::fmt;
 use log::{info, warn, error, debug};
 use std::io::Cursor;
 use anyhow::{Result, Context, bail};
@@ -9,8 +10,13 @@ use html5ever::driver::ParseOpts;
 use html5ever::tendril::TendrilSink;
 use html5ever::tree_builder::TreeBuilderOpts;
 use html5ever::{parse_document, serialize};
+use html5ever::serialize::{SerializeOpts, TraversalScope};
 use rcdom::{RcDom, SerializableHandle, Handle, NodeData};
 use std::cell::RefCell;
+use url::Url;
+use crate::config::{Site, BlogPost};
+use crate::selector::{self, Selector};
+use crate::score;
 
 pub fn walk_tags(src: &str) -> Result<()> {
     let dom = build_dom(src)?;
@@ -18,8 +24,8 @@ pub fn walk_tags(src: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn extract_article_string(src: &str) -> Result<String> {
-    let ((dom, node), type_) = extract_article(src)?;
+pub fn extract_article_string(src: &str, rules: &ExtractRules) -> Result<String> {
+    let ((dom, node), type_) = extract_article(src, rules)?;
     let s = serialize_dom(&node)
         .context("serializing dom")?;
     Ok(s)
@@ -27,13 +33,84 @@ pub fn extract_article_string(src: &str) -> Result<String> {
 
 pub type SubDom = (RcDom, Handle);
 
-pub fn extract_article(src: &str) -> Result<(SubDom, CandidateType)> {
+/// Extraction rules for a post, from its site's entry in `sites.toml` and
+/// its own overrides
+#[derive(Debug, Default)]
+pub struct ExtractRules {
+    pub article: Option<Selector>,
+    pub remove: Vec<Selector>,
+    pub title: Option<Selector>,
+}
+
+impl ExtractRules {
+    pub fn from_site(site: Option<&Site>) -> Result<ExtractRules> {
+        let site = match site {
+            Some(site) => site,
+            None => return Ok(ExtractRules::default()),
+        };
+        let parse = |s: &String| {
+            Selector::parse(s)
+                .with_context(|| format!("in the rules for {}", site.host))
+        };
+        Ok(ExtractRules {
+            article: site.article.as_ref().map(parse).transpose()?,
+            remove: site.remove.iter().map(parse).collect::<Result<_>>()?,
+            title: site.title.as_ref().map(parse).transpose()?,
+        })
+    }
+
+    /// Applies the post's overrides, whose article selector replaces the
+    /// site's and whose excluded elements are removed as well as the site's
+    pub fn with_post(mut self, post: &BlogPost) -> Result<ExtractRules> {
+        let parse = |s: &String| {
+            Selector::parse(s)
+                .with_context(|| format!("in the config for {}", post.url))
+        };
+        if let Some(article) = &post.selector {
+            self.article = Some(parse(article)?);
+        }
+        for exclude in &post.exclude {
+            self.remove.push(parse(exclude)?);
+        }
+        Ok(self)
+    }
+}
+
+/// Finds the article, using the site's article selector if it has one and
+/// it matches, then the built-in candidates, then the best scoring element.
+/// Elements matching the site's remove selectors are removed from it.
+pub fn extract_article(src: &str, rules: &ExtractRules) -> Result<(SubDom, CandidateType)> {
     let dom = build_dom(src)?;
-    let candidate = find_article(&dom.document);
+    let site_candidate = rules.article.as_ref().and_then(|article| {
+        let node = selector::select_first(&dom.document, article);
+        if node.is_none() {
+            warn!("article selector '{}' matched nothing", article);
+        }
+        node.map(|node| Candidate {
+            type_: CandidateType::Site,
+            node,
+        })
+    });
+    let candidate = site_candidate
+        .or_else(|| find_article(&dom.document))
+        .or_else(|| {
+            let (node, score) = score::best_candidate(&dom.document)?;
+            debug!("no article element, so scored {} at {:.1}", describe(&node), score);
+            Some(Candidate {
+                type_: CandidateType::Scored(score),
+                node,
+            })
+        });
     match candidate {
         Some(candidate) => {
             let node = candidate.node;
             let type_ = candidate.type_;
+            for selector in &rules.remove {
+                let removed = remove(&node, selector);
+                if removed > 0 {
+                    debug!("removed {} elements matching '{}'", removed, selector);
+                }
+            }
             Ok(((dom, node), type_))
         }
         None => {
@@ -42,12 +119,116 @@ pub fn extract_article(src: &str) -> Result<(SubDom, CandidateType)> {
     }
 }
 
+/// Removes the elements under `root` that match the selector, returning how
+/// many were removed
+pub fn remove(root: &Handle, selector: &Selector) -> usize {
+    let mut removed = 0;
+    let mut children = root.children.borrow_mut();
+    children.retain(|child| {
+        let matches = selector.matches(child);
+        if matches {
+            removed += 1;
+        }
+        !matches
+    });
+    for child in children.iter() {
+        removed += remove(child, selector);
+    }
+    removed
+}
+
+/// Describes an element for debugging, like `div#content.post.entry`
+pub fn describe(node: &Handle) -> String {
+    let (name, attrs) = match &node.data {
+        NodeData::Element { name, attrs, .. } => (name, attrs),
+        _ => return format!("{:?}", node.data),
+    };
+    let mut desc = name.local.to_string();
+    if let Some(id) = find_id_attr(attrs) {
+        desc.push('#');
+        desc.push_str(&id);
+    }
+    if let Some(class) = find_attr(attrs, "class") {
+        for class in class.split_whitespace() {
+            desc.push('.');
+            desc.push_str(class);
+        }
+    }
+    desc
+}
+
+/// Serializes an element, including its own tags
+pub fn outer_html(node: &Handle) -> Result<String> {
+    let opts = SerializeOpts {
+        traversal_scope: TraversalScope::IncludeNode,
+        ..Default::default()
+    };
+    let node: SerializableHandle = node.clone().into();
+    let mut buf = Vec::new();
+    serialize(&mut buf, &node, opts)?;
+    let html = String::from_utf8(buf).context("serialized dom not utf8")?;
+    Ok(html)
+}
+
+/// The text of an element, with whitespace collapsed
+pub fn text(node: &Handle) -> String {
+    let mut text = String::new();
+    walk_dom_fn(node, &mut |node| {
+        if let NodeData::Text { contents } = &node.data {
+            text.push_str(&contents.borrow());
+        }
+    });
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The text of the first element in the page that matches the selector,
+/// with whitespace collapsed
+pub fn select_text(src: &str, selector: &Selector) -> Result<Option<String>> {
+    let dom = build_dom(src)?;
+    let node = match selector::select_first(&dom.document, selector) {
+        Some(node) => node,
+        None => return Ok(None),
+    };
+    let text = text(&node);
+    if text.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(text))
+    }
+}
+
 pub fn extract_dom(src: &str) -> Result<SubDom> {
     let dom = build_dom(src)?;
     let node = dom.document.clone();
     Ok((dom, node))
 }
 
+/// Finds the page's `<link rel="canonical">`, resolved against the URL the
+/// page was fetched from
+pub fn canonical_url(src: &str, base_url: &Url) -> Result<Option<Url>> {
+    let dom = build_dom(src)?;
+    let mut href = None;
+    walk_dom_fn(&dom.document, &mut |node| {
+        if let NodeData::Element { name, attrs, .. } = &node.data {
+            if href.is_none() && name.local.as_ref() == "link" {
+                let rel = find_attr(attrs, "rel").unwrap_or_default();
+                if rel.split_whitespace().any(|r| r.eq_ignore_ascii_case("canonical")) {
+                    href = find_attr(attrs, "href");
+                }
+            }
+        }
+    });
+
+    match href {
+        Some(href) => {
+            let url = base_url.join(href.trim())
+                .with_context(|| format!("invalid canonical url '{}'", href))?;
+            Ok(Some(url))
+        }
+        None => Ok(None),
+    }
+}
+
 fn build_dom(src: &str) -> Result<RcDom> {
     let opts = ParseOpts {
         tree_builder: TreeBuilderOpts {
@@ -95,108 +276,111 @@ fn walk_children(dom: &Handle, lvl: u32) {
 }
 
 fn find_article(dom: &Handle) -> Option<Candidate> {
+    let candidate_selectors: Vec<(CandidateType, Selector)> = CANDIDATE_SELECTORS.iter()
+        .map(|(type_, selector)| (*type_, Selector::parse(selector).expect("bad selector")))
+        .collect();
     let mut candidate = None;
-    find_article_(dom, &mut candidate);
+    walk_dom_fn(dom, &mut |node| {
+        find_article_(node, &candidate_selectors, &mut candidate);
+    });
     candidate
 }
 
-#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub enum CandidateType {
+    /// Chosen by the site's rules or the post's overrides
+    Site,
     Article,
     Main,
     ContentDiv,
     Dreamwidth,
+    /// The best of the fallback scoring, with its score
+    Scored(f64),
+}
+
+impl fmt::Display for CandidateType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CandidateType::Site => write!(f, "site"),
+            CandidateType::Article => write!(f, "article"),
+            CandidateType::Main => write!(f, "main"),
+            CandidateType::ContentDiv => write!(f, "content-div"),
+            CandidateType::Dreamwidth => write!(f, "dreamwidth"),
+            CandidateType::Scored(score) => write!(f, "scored {:.1}", score),
+        }
+    }
 }
 
+/// The built-in article candidates. A node matching more than one takes the
+/// last.
+static CANDIDATE_SELECTORS: &[(CandidateType, &str)] = &[
+    (CandidateType::Article, "article"),
+    (CandidateType::Main, "main"),
+    // As in bcantrill's pages
+    (CandidateType::ContentDiv, "div#content"),
+    // dreamwidth.org
+    (CandidateType::Dreamwidth, r#"div[id^="entry-"]:not([id^="entry-wrapper-"])"#),
+];
+
 struct Candidate {
     type_: CandidateType,
     node: Handle,
 }
 
-fn find_article_(dom: &Handle, candidate: &mut Option<Candidate>) {
-    match &dom.data {
-        NodeData::Element { name, attrs, .. } => {
-            let name = name.local.as_ref();
-            let id_attr = find_id_attr(attrs);
-            let dreamwidth_entry_id = id_attr.as_ref().map(|id| {
-                id.starts_with("entry-")
-                    && !id.starts_with("entry-wrapper-")
-            }).unwrap_or(false);
-            let mut candidate_type = None;
-
-            if name == "article" {
-                candidate_type = Some(CandidateType::Article);
-            }
-            if name == "main" {
-                candidate_type = Some(CandidateType::Main);
-            }
-            // As in bcantrill's pages
-            if name == "div" && id_attr == Some("content".to_string()) {
-                candidate_type = Some(CandidateType::ContentDiv);
-            }
-            // dreamwidth.org
-            if name == "div" && dreamwidth_entry_id {
-                candidate_type = Some(CandidateType::Dreamwidth);
-            }
+fn find_article_(dom: &Handle, selectors: &[(CandidateType, Selector)], candidate: &mut Option<Candidate>) {
+    let candidate_type = selectors.iter().rev()
+        .find(|(_, selector)| selector.matches(dom))
+        .map(|(type_, _)| *type_);
 
-            if let Some(candidate_type) = candidate_type {
-                match candidate {
-                    None => {
-                        *candidate = Some(Candidate {
+    if let Some(candidate_type) = candidate_type {
+        match candidate {
+            None => {
+                *candidate = Some(Candidate {
+                    type_: candidate_type,
+                    node: dom.clone(),
+                });
+            }
+            Some(ref mut candidate) => {
+                warn!("multiple article candidates");
+                let old_candidate_type = candidate.type_;
+                let upgraded;
+                match (old_candidate_type, candidate_type) {
+                    (CandidateType::Main, CandidateType::Article) => {
+                        *candidate = Candidate {
                             type_: candidate_type,
                             node: dom.clone(),
-                        });
+                        };
+                        upgraded = true;
                     }
-                    Some(ref mut candidate) => {
-                        warn!("multiple article candidates");
-                        let old_candidate_type = candidate.type_;
-                        let upgraded;
-                        match (old_candidate_type, candidate_type) {
-                            (CandidateType::Main, CandidateType::Article) => {
-                                *candidate = Candidate {
-                                    type_: candidate_type,
-                                    node: dom.clone(),
-                                };
-                                upgraded = true;
-                            }
-                            (CandidateType::ContentDiv, CandidateType::Dreamwidth) => {
-                                *candidate = Candidate {
-                                    type_: candidate_type,
-                                    node: dom.clone(),
-                                };
-                                upgraded = true;
-                            }
-                            _ => {
-                                upgraded = false;
-                            }
-                        }
-                        if upgraded {
-                            warn!("upgrading article from {:?} to {:?}", old_candidate_type, candidate_type);
-                        } else {
-                            warn!("new candidate: {:?}", candidate_type);
-                            warn!("using old candidate: {:?}", old_candidate_type);
-                        }
+                    (CandidateType::ContentDiv, CandidateType::Dreamwidth) => {
+                        *candidate = Candidate {
+                            type_: candidate_type,
+                            node: dom.clone(),
+                        };
+                        upgraded = true;
+                    }
+                    _ => {
+                        upgraded = false;
                     }
                 }
+                if upgraded {
+                    warn!("upgrading article from {:?} to {:?}", old_candidate_type, candidate_type);
+                } else {
+                    warn!("new candidate: {:?}", candidate_type);
+                    warn!("using old candidate: {:?}", old_candidate_type);
+                }
             }
-
-            find_article_children(dom, candidate);
-        }
-        _ => {
-            find_article_children(dom, candidate);
         }
     }
 }
 
-fn find_article_children(dom: &Handle, candidate: &mut Option<Candidate>) {
-    for child in dom.children.borrow().iter() {
-        find_article_(&child, candidate);
-    }
+fn find_id_attr(attrs: &RefCell<Vec<Attribute>>) -> Option<String> {
+    find_attr(attrs, "id")
 }
 
-fn find_id_attr(attrs: &RefCell<Vec<Attribute>>) -> Option<String> {
+pub fn find_attr(attrs: &RefCell<Vec<Attribute>>, name: &str) -> Option<String> {
     for attr in &*attrs.borrow() {
-        if &attr.name.local == "id" {
+        if &attr.name.local == name {
             return Some(attr.value.to_string());
         }
     }
@@ -216,6 +400,13 @@ fn serialize_dom(dom: &Handle) -> Result<String> {
     Ok(doc)
 }
 
+pub fn parent(node: &Handle) -> Option<Handle> {
+    let weak = node.parent.take()?;
+    let parent = weak.upgrade();
+    node.parent.set(Some(weak));
+    parent
+}
+
 pub fn walk_dom_fn(dom: &Handle, f: &mut impl FnMut(&Handle)) {
     f(dom);
     walk_dom_fn_children(dom, f);
@@ -1,67 +1,1168 @@
-use log::debug;
-use std::io::ErrorKind;
+use log::{info, debug, warn};
+use std::io::{self, ErrorKind};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::fs::{self, File};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use blake2::{Blake2b, Digest};
-use anyhow::{Result, Context, anyhow};
+use anyhow::{Result, Context, anyhow, bail};
 use reqwest::StatusCode;
 use reqwest::blocking::Client as HttpClient;
+use reqwest::blocking::{Request, RequestBuilder, Response};
+use reqwest::header::{self, HeaderMap};
+use reqwest::redirect;
+use serde::{Serialize, Deserialize};
 use url::Url;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, Condvar};
+use std::fmt;
+use std::thread;
+use crate::failures::{FailureLedger, FailureKind};
+use crate::charset;
+use crate::archive;
+use crate::robots::Robots;
+use crate::date::DateTime;
 
+static FAILURES_FILE: &'static str = "failures.toml";
+static MAX_REDIRECTS: usize = 10;
+static ROBOTS_PATH: &'static str = "/robots.txt";
+/// The wait before the first retry, doubled for each one after
+static RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// Longer waits asked for by `Retry-After` give up instead
+static MAX_RETRY_WAIT: Duration = Duration::from_secs(5 * 60);
+
+/// A cache of fetched URLs on disk. It can be shared between threads, and
+/// makes requests as the `CrawlPolicy` says: spaced out and limited per host,
+/// retried when the server is overloaded, and only where robots.txt allows.
+///
+/// Each entry is stored as the response body, named by the hash of its URL,
+/// next to a `<hash>.toml` file of `CacheMeta`. URLs that fail to fetch are
+/// recorded in a `FailureLedger` and not requested again unless the policy
+/// says to retry them. If the policy names an archive, URLs that can't be
+/// fetched are cached from a snapshot in the archive instead.
 pub struct HttpCache {
     dir: PathBuf,
     client: HttpClient,
+    hosts: HostLimiter,
+    policy: CachePolicy,
+    crawl: CrawlPolicy,
+    failures: FailureLedger,
+    /// Parsed robots.txt files, by origin
+    robots: Mutex<HashMap<String, Arc<Robots>>>,
+}
+
+/// When cached entries are checked against the server
+#[derive(Debug, Clone, Default)]
+pub struct CachePolicy {
+    /// Revalidate every entry, with a conditional request if possible
+    pub refresh: bool,
+    /// Revalidate entries fetched longer ago than this
+    pub max_age: Option<Duration>,
+    /// Request URLs that are in the failure ledger
+    pub retry_failed: bool,
+    /// Never use the network, and fail with `NotCached` on a cache miss
+    pub offline: bool,
+    /// A Wayback-style archive to get snapshots from when the origin fails
+    pub archive: Option<Url>,
+}
+
+/// How requests are made to servers
+#[derive(Debug, Clone)]
+pub struct CrawlPolicy {
+    pub user_agent: String,
+    /// Maximum number of concurrent requests to a host
+    pub max_per_host: usize,
+    /// Minimum time between the starts of requests to a host
+    pub delay: Duration,
+    pub connect_timeout: Duration,
+    /// Time allowed for a whole request, including reading the body
+    pub timeout: Duration,
+    /// Times a request is retried after a 429 or 5xx response
+    pub retries: u32,
+    /// Fetch URLs that robots.txt disallows
+    pub ignore_robots: bool,
+}
+
+/// The state of a cache entry under the cache policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryStatus {
+    /// Cached, and won't be requested again
+    Fresh,
+    /// Cached, but will be revalidated or fetched again
+    Stale,
+    /// Not cached, because fetching it failed for good
+    Failed,
+    Missing,
+}
+
+impl fmt::Display for EntryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lbl = match self {
+            EntryStatus::Fresh => "fresh",
+            EntryStatus::Stale => "stale",
+            EntryStatus::Failed => "failed",
+            EntryStatus::Missing => "missing",
+        };
+        write!(f, "{}", lbl)
+    }
+}
+
+/// A cache miss in offline mode
+#[derive(Debug)]
+pub struct NotCached {
+    pub url: Url,
+}
+
+impl fmt::Display for NotCached {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not cached, and the network is off", self.url)
+    }
+}
+
+impl std::error::Error for NotCached { }
+
+/// What we know about a cached response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheMeta {
+    pub url: Url,
+    /// Seconds since the Unix epoch of the last fetch or revalidation
+    pub fetched_at: u64,
+    pub status: u16,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_type: Option<String>,
+    /// Whether the body is stored as received. Older versions stored pages
+    /// decoded to UTF-8.
+    #[serde(default)]
+    pub raw: bool,
+    /// `content_hash` of the body, to detect corruption
+    pub body_hash: Option<String>,
+    /// The URL the body was fetched from, after following redirects
+    #[serde(default)]
+    pub final_url: Option<Url>,
+    /// The headers of the request for `final_url`, as sent
+    #[serde(default)]
+    pub request_headers: Vec<(String, String)>,
+    /// The headers of the response, as received
+    #[serde(default)]
+    pub response_headers: Vec<(String, String)>,
+    /// The archived copy the body came from, if the origin couldn't be
+    /// fetched
+    #[serde(default)]
+    pub snapshot: Option<Url>,
+    /// The redirects followed to get to `final_url`, in order
+    #[serde(default)]
+    pub redirects: Vec<Redirect>,
+}
+
+/// A body in the cache directory and its metadata
+pub struct CacheEntry {
+    pub hash: String,
+    /// The size of the body, or `None` if there's only metadata
+    pub size: Option<u64>,
+    /// The metadata, if there is any and it can be read
+    pub meta: std::result::Result<Option<CacheMeta>, String>,
+}
+
+/// A cached page, as received from the server
+pub struct Page {
+    pub body: Vec<u8>,
+    pub content_type: Option<String>,
+    /// Where the page came from, if it's an archived copy
+    pub snapshot: Option<Url>,
+}
+
+impl Page {
+    /// Decodes the page according to its declared or detected charset.
+    /// Snapshots are stripped of what the archive added to them.
+    pub fn text(&self) -> String {
+        let text = charset::decode(&self.body, self.content_type.as_deref());
+        match &self.snapshot {
+            Some(snapshot) => archive::strip_toolbar(&text, snapshot),
+            None => text,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Redirect {
+    pub url: Url,
+    pub status: u16,
 }
 
 impl HttpCache {
-    pub fn new(dir: PathBuf) -> HttpCache {
-        HttpCache {
-            dir, client: HttpClient::new(),
+    pub fn new(dir: PathBuf, policy: CachePolicy, crawl: CrawlPolicy) -> Result<HttpCache> {
+        let failures = FailureLedger::load(dir.join(FAILURES_FILE))?;
+        // Redirects are followed by hand, to record them
+        let client = HttpClient::builder()
+            .redirect(redirect::Policy::none())
+            .connect_timeout(crawl.connect_timeout)
+            .timeout(crawl.timeout)
+            .build()
+            .context("building http client")?;
+        Ok(HttpCache {
+            dir, client,
+            hosts: HostLimiter::new(crawl.max_per_host, crawl.delay),
+            policy,
+            crawl,
+            failures,
+            robots: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn failures(&self) -> &FailureLedger {
+        &self.failures
+    }
+
+    /// Reads the metadata of a cached URL, if it has any
+    pub fn meta(&self, url: &Url) -> Result<Option<CacheMeta>> {
+        read_meta(&self.meta_path(&url_hash(url)))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Lists the entries in the cache directory, by hash. Files that aren't
+    /// cache entries are returned as `Err` with their file name.
+    pub fn entries(&self) -> Result<Vec<std::result::Result<CacheEntry, String>>> {
+        let mut bodies = BTreeMap::new();
+        let mut metas = BTreeSet::new();
+        let mut others = Vec::new();
+
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("reading cache dir"),
+        };
+        for file in dir {
+            let file = file.context("reading cache dir")?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            if is_hash(&name) {
+                let size = file.metadata().context("reading cache entry")?.len();
+                bodies.insert(name, size);
+            } else if name.strip_suffix(".toml").map(is_hash).unwrap_or(false) {
+                metas.insert(name.trim_end_matches(".toml").to_string());
+            } else if name != FAILURES_FILE {
+                others.push(name);
+            }
+        }
+
+        let hashes: BTreeSet<&String> = bodies.keys().chain(metas.iter()).collect();
+        let mut entries: Vec<_> = hashes.into_iter()
+            .map(|hash| {
+                let meta = if metas.contains(hash) {
+                    read_meta(&self.meta_path(hash)).map_err(|e| format!("{:#}", e))
+                } else {
+                    Ok(None)
+                };
+                Ok(CacheEntry {
+                    hash: hash.clone(),
+                    size: bodies.get(hash).copied(),
+                    meta,
+                })
+            })
+            .collect();
+        entries.extend(others.into_iter().map(Err));
+        Ok(entries)
+    }
+
+    pub fn read_entry(&self, hash: &str) -> Result<Vec<u8>> {
+        fs::read(self.dir.join(hash))
+            .context("reading cache entry")
+    }
+
+    /// Adds an entry fetched elsewhere, under the URL in its metadata
+    pub fn insert(&self, body: &[u8], meta: &CacheMeta) -> Result<()> {
+        let hash = url_hash(&meta.url);
+        fs::create_dir_all(&self.dir)
+            .context("creating cache dir")?;
+        write_atomic(&self.dir.join(&hash), body)
+            .context("writing cache")?;
+        write_meta(&self.meta_path(&hash), meta)
+    }
+
+    /// Deletes an entry's body and metadata
+    pub fn remove(&self, hash: &str) -> Result<()> {
+        for path in &[self.dir.join(hash), self.meta_path(hash)] {
+            match fs::remove_file(path) {
+                Ok(()) => { }
+                Err(e) if e.kind() == ErrorKind::NotFound => { }
+                Err(e) => return Err(e).context("removing cache entry"),
+            }
         }
+        Ok(())
     }
 
-    pub fn get(&mut self, url: &Url) -> Result<String> {
+    /// Makes the cache entry of `from` available under `to` as well, so a
+    /// renamed URL doesn't need to be fetched again
+    pub fn alias(&self, from: &Url, to: &Url) -> Result<()> {
+        let from_hash = url_hash(from);
+        let to_hash = url_hash(to);
+        fs::copy(self.dir.join(&from_hash), self.dir.join(&to_hash))
+            .context("copying cache entry")?;
+        if let Some(mut meta) = read_meta(&self.meta_path(&from_hash))? {
+            meta.url = to.clone();
+            write_meta(&self.meta_path(&to_hash), &meta)?;
+        }
+        Ok(())
+    }
+
+    /// Gets a page, undecoded. Pages cached before bodies were stored as
+    /// received are fetched again.
+    pub fn get(&self, url: &Url) -> Result<Page> {
+        let (body, meta) = self.get_body(url, true)?;
+        let snapshot = meta.as_ref().and_then(|meta| meta.snapshot.clone());
+        let content_type = match meta {
+            Some(meta) if meta.raw => meta.content_type,
+            // Served from an old entry, offline or because it can't be
+            // fetched again
+            _ => Some("text/html; charset=utf-8".to_string()),
+        };
+        Ok(Page {
+            body,
+            content_type,
+            snapshot,
+        })
+    }
+
+    /// Like `get`, but for binary resources like images.
+    pub fn get_bytes(&self, url: &Url) -> Result<Vec<u8>> {
+        let (body, _) = self.get_body(url, false)?;
+        Ok(body)
+    }
+
+    /// Reports how a page would be served, without fetching it
+    pub fn status(&self, url: &Url) -> Result<EntryStatus> {
+        let hash = url_hash(url);
+        if !self.dir.join(&hash).exists() {
+            if self.failures.get(url).map(|failure| failure.is_permanent()).unwrap_or(false) {
+                return Ok(EntryStatus::Failed);
+            }
+            return Ok(EntryStatus::Missing);
+        }
+        let meta = read_meta(&self.meta_path(&hash))?;
+        let decoded = !meta.as_ref().map(|meta| meta.raw).unwrap_or(false);
+        if self.policy.offline {
+            Ok(EntryStatus::Fresh)
+        } else if decoded || self.needs_revalidation(meta.as_ref()) {
+            Ok(EntryStatus::Stale)
+        } else {
+            Ok(EntryStatus::Fresh)
+        }
+    }
+
+    fn meta_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.toml", hash))
+    }
+
+    fn get_body(&self, url: &Url, require_raw: bool) -> Result<(Vec<u8>, Option<CacheMeta>)> {
         let hash = url_hash(url);
         debug!("fetching {}", url);
         debug!("hash {}", hash);
         let cache_path = self.dir.join(&hash);
-        let cached = fs::read_to_string(&cache_path);
-        match cached {
-            Ok(cached) => {
-                debug!("cache hit for {}", url);
-                Ok(cached)
-            },
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                let body = fetch_url(&mut self.client, url)?;
+        let meta_path = self.meta_path(&hash);
+
+        let cached = match fs::read(&cache_path) {
+            Ok(cached) => Some(cached),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context("opening cache"),
+        };
+        let meta = match read_meta(&meta_path) {
+            Ok(meta) => meta,
+            Err(e) => {
+                warn!("ignoring cache metadata for {}: {:#}", url, e);
+                None
+            }
+        };
+
+        // Transient failures are retried, and posts flagged by robots.txt
+        // are fetched again when ignoring it
+        let known_dead = if self.policy.retry_failed {
+            None
+        } else {
+            self.failures.get(url)
+                .filter(|failure| failure.is_permanent())
+                .filter(|failure| !(failure.kind == FailureKind::Robots && self.crawl.ignore_robots))
+        };
+
+        let cached = match (cached, &known_dead) {
+            (Some(cached), _) if self.policy.offline => {
+                debug!("offline cache hit for {}", url);
+                return Ok((cached, meta));
+            }
+            (None, _) if self.policy.offline => {
+                return Err(NotCached { url: url.clone() }.into());
+            }
+            (Some(cached), _) => cached,
+            (None, Some(failure)) => {
+                let error = match failure.status {
+                    Some(status) => status.to_string(),
+                    None => failure.kind.to_string(),
+                };
+                if failure.kind == FailureKind::Robots && self.policy.archive.is_none() {
+                    bail!("skipping {}, which robots.txt disallows; \
+                           use --ignore-robots to fetch it anyway", url);
+                }
+                if self.policy.archive.is_none() {
+                    bail!("skipping known-dead url {} ({} error, {} attempts); \
+                           use --retry-failed to try again",
+                          url, error, failure.attempts);
+                }
+                debug!("{} is known dead ({} error)", url, error);
+                let (body, meta) = self.fetch_snapshot(url)?;
+                self.insert(&body, &meta)?;
+                return Ok((body, Some(meta)));
+            }
+            (None, None) => {
+                let fetched = match self.fetch_recording_failures(url, None) {
+                    Ok(fetched) => fetched,
+                    Err(e) if self.policy.archive.is_some() => {
+                        warn!("{:#}", e);
+                        Some(self.fetch_snapshot(url)?)
+                    }
+                    Err(e) => return Err(e),
+                };
+                let (body, meta) = fetched
+                    .expect("unconditional request not modified");
                 debug!("writing cache for {} to {}", url, cache_path.display());
                 fs::create_dir_all(&self.dir)
                     .context("creating cache dir")?;
-                fs::write(cache_path, &body)
+                write_atomic(&cache_path, &body)
                     .context("writing cache")?;
-                Ok(body)
-            },
-            e => e.context("opening cache"),
+                write_meta(&meta_path, &meta)?;
+                return Ok((body, Some(meta)));
+            }
+        };
+
+        let decoded = require_raw && !meta.as_ref().map(|meta| meta.raw).unwrap_or(false);
+        if known_dead.is_some() || (!decoded && !self.needs_revalidation(meta.as_ref())) {
+            debug!("cache hit for {}", url);
+            return Ok((cached, meta));
+        }
+
+        let conditional_meta = if decoded {
+            debug!("refetching {}, which was cached decoded", url);
+            None
+        } else {
+            debug!("revalidating {}", url);
+            meta.as_ref()
+        };
+        match self.fetch_recording_failures(url, conditional_meta) {
+            Ok(Some((body, meta))) => {
+                debug!("updating cache for {}", url);
+                write_atomic(&cache_path, &body)
+                    .context("writing cache")?;
+                write_meta(&meta_path, &meta)?;
+                Ok((body, Some(meta)))
+            }
+            Ok(None) => {
+                debug!("not modified: {}", url);
+                let mut meta = meta.expect("conditional request without metadata");
+                meta.fetched_at = now();
+                write_meta(&meta_path, &meta)?;
+                Ok((cached, Some(meta)))
+            }
+            Err(e) => {
+                warn!("using stale cache for {}: {:#}", url, e);
+                Ok((cached, meta))
+            }
+        }
+    }
+
+    /// Fetches the archive's snapshot of a URL that can't be fetched, from
+    /// around when it started failing. The entry is cached under the original
+    /// URL, so it's served from the cache until the origin is back.
+    fn fetch_snapshot(&self, url: &Url) -> Result<(Vec<u8>, CacheMeta)> {
+        let archive = self.policy.archive.as_ref().expect("no archive");
+        let failed_at = self.failures.get(url)
+            .map(|failure| failure.first_failed)
+            .unwrap_or_else(now);
+        let snapshot_url = archive::snapshot_url(archive, failed_at, url)?;
+        info!("fetching snapshot {}", snapshot_url);
+        let (body, mut meta) = self.fetch(&snapshot_url, None)
+            .with_context(|| format!("no snapshot of {} in the archive", url))?
+            .expect("unconditional request not modified");
+        let served_from = meta.final_url.take().unwrap_or(snapshot_url);
+        meta.url = url.clone();
+        meta.final_url = Some(url.clone());
+        meta.snapshot = Some(served_from);
+        meta.redirects.clear();
+        Ok((body, meta))
+    }
+
+    fn needs_revalidation(&self, meta: Option<&CacheMeta>) -> bool {
+        if self.policy.refresh {
+            return true;
+        }
+        match (self.policy.max_age, meta) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(max_age), Some(meta)) => {
+                now().saturating_sub(meta.fetched_at) > max_age.as_secs()
+            }
+        }
+    }
+
+    /// Like `fetch`, but records failures in the failure ledger, and clears
+    /// them on success
+    fn fetch_recording_failures(&self, url: &Url, meta: Option<&CacheMeta>) -> Result<Option<(Vec<u8>, CacheMeta)>> {
+        match self.fetch(url, meta) {
+            Ok(fetched) => {
+                self.failures.clear(url)?;
+                Ok(fetched)
+            }
+            Err(e) => {
+                self.failures.record(url, e.kind, e.status, e.message.clone(), now())?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Fetches the URL, conditionally if there's metadata from a previous
+    /// fetch. Returns `None` if the server says the cached copy is current.
+    fn fetch(&self, url: &Url, meta: Option<&CacheMeta>) -> Result<Option<(Vec<u8>, CacheMeta)>, FetchError> {
+        let mut redirects = Vec::new();
+        let mut current = url.clone();
+        // The permit for the last request is held while its body is read
+        let (resp, request_headers, _permit) = loop {
+            if !self.robots_allow(&current)? {
+                return Err(FetchError {
+                    kind: FailureKind::Robots,
+                    status: None,
+                    message: format!("{} is disallowed by robots.txt", current),
+                });
+            }
+            let mut req = self.client.get(current.clone())
+                .header(header::USER_AGENT, self.crawl.user_agent.as_str());
+            if let Some(meta) = meta {
+                req = conditional(req, meta);
+            }
+            let req = req.build()?;
+            let request_headers = header_pairs(req.headers());
+            let (resp, permit) = self.execute_with_retries(req)?;
+            let status = resp.status();
+            let location = header_string(resp.headers(), header::LOCATION);
+            let location = match location {
+                Some(location) if status.is_redirection() && status != StatusCode::NOT_MODIFIED => location,
+                _ => break (resp, request_headers, permit),
+            };
+            if redirects.len() >= MAX_REDIRECTS {
+                return Err(FetchError {
+                    kind: FailureKind::Other,
+                    status: Some(status.as_u16()),
+                    message: format!("too many redirects fetching {}", url),
+                });
+            }
+            let next = current.join(&location)
+                .map_err(|e| FetchError {
+                    kind: FailureKind::Other,
+                    status: Some(status.as_u16()),
+                    message: format!("invalid redirect from {} to '{}': {}", current, location, e),
+                })?;
+            debug!("{} redirects to {}", current, next);
+            redirects.push(Redirect {
+                url: current,
+                status: status.as_u16(),
+            });
+            current = next;
+        };
+        debug!("printing headers");
+        for (key, value) in resp.headers() {
+            debug!("{}: {:?}", key, value);
+        }
+        let status = resp.status();
+        if status == StatusCode::NOT_MODIFIED && meta.is_some() {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(FetchError {
+                kind: FailureKind::Status,
+                status: Some(status.as_u16()),
+                message: format!("failed to fetch url {}: {}", url, status),
+            });
+        }
+        let headers = resp.headers().clone();
+        let body = resp.bytes()?.to_vec();
+        let headers = &headers;
+        let new_meta = CacheMeta {
+            url: url.clone(),
+            fetched_at: now(),
+            status: status.as_u16(),
+            etag: header_string(headers, header::ETAG),
+            last_modified: header_string(headers, header::LAST_MODIFIED),
+            content_type: header_string(headers, header::CONTENT_TYPE),
+            raw: true,
+            body_hash: Some(content_hash(&body)),
+            final_url: Some(current),
+            request_headers,
+            response_headers: header_pairs(headers),
+            snapshot: None,
+            redirects,
+        };
+        Ok(Some((body, new_meta)))
+    }
+
+    /// Makes a request, retrying with exponential backoff while the server
+    /// responds with 429 or a 5xx status, or waiting as long as it asks with
+    /// `Retry-After`. Returns the last response, with the host permit it was
+    /// made under.
+    fn execute_with_retries(&self, req: Request) -> Result<(Response, HostPermit<'_>), FetchError> {
+        let mut attempt = 0;
+        loop {
+            let retry = match req.try_clone() {
+                Some(retry) if attempt < self.crawl.retries => retry,
+                _ => {
+                    let permit = self.hosts.acquire(req.url());
+                    return Ok((self.client.execute(req)?, permit));
+                }
+            };
+            let permit = self.hosts.acquire(retry.url());
+            let resp = self.client.execute(retry)?;
+            let status = resp.status();
+            if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+                return Ok((resp, permit));
+            }
+            let backoff = 2u32.checked_pow(attempt)
+                .and_then(|factor| RETRY_BACKOFF.checked_mul(factor))
+                .unwrap_or(MAX_RETRY_WAIT)
+                .min(MAX_RETRY_WAIT);
+            let wait = match header_string(resp.headers(), header::RETRY_AFTER) {
+                Some(retry_after) => match parse_retry_after(&retry_after) {
+                    Some(wait) if wait > MAX_RETRY_WAIT => {
+                        debug!("not retrying {}, which asks to wait {}s", req.url(), wait.as_secs());
+                        return Ok((resp, permit));
+                    }
+                    Some(wait) => wait,
+                    None => backoff,
+                },
+                None => backoff,
+            };
+            drop(permit);
+            warn!("{} responded {}; retrying in {}s", req.url(), status, wait.as_secs());
+            thread::sleep(wait);
+            attempt += 1;
+        }
+    }
+
+    /// Checks the URL against its host's robots.txt, fetching it the first
+    /// time. Hosts whose robots.txt can't be fetched allow everything.
+    fn robots_allow(&self, url: &Url) -> Result<bool, FetchError> {
+        if self.crawl.ignore_robots {
+            return Ok(true);
+        }
+        let origin = url.origin().ascii_serialization();
+        let robots = self.robots.lock().expect("poisoned").get(&origin).cloned();
+        let robots = match robots {
+            Some(robots) => robots,
+            None => {
+                // Unreachable robots.txt files aren't remembered, so they're
+                // tried again for the host's next URL
+                let robots = Arc::new(self.fetch_robots(url)?);
+                self.robots.lock().expect("poisoned")
+                    .insert(origin, robots.clone());
+                robots
+            }
+        };
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        Ok(robots.allowed(&path))
+    }
+
+    /// Fetches the robots.txt of a URL's host. Redirects aren't followed,
+    /// as a redirect to another host should be checked against that host's
+    /// robots.txt when the page redirects there too.
+    ///
+    /// As RFC 9309 says, a missing robots.txt (a 4xx) allows everything,
+    /// while one that is unreachable (a 5xx or network error) disallows
+    /// everything, which is returned as an error.
+    fn fetch_robots(&self, url: &Url) -> Result<Robots, FetchError> {
+        let mut robots_url = url.clone();
+        robots_url.set_path(ROBOTS_PATH);
+        robots_url.set_query(None);
+        robots_url.set_fragment(None);
+        let origin = url.origin().ascii_serialization();
+        let unreachable = |e: FetchError| FetchError {
+            message: format!("robots.txt for {} is unreachable: {}", origin, e.message),
+            ..e
+        };
+        debug!("fetching {}", robots_url);
+        let req = self.client.get(robots_url)
+            .header(header::USER_AGENT, self.crawl.user_agent.as_str())
+            .build()?;
+        let (resp, _permit) = self.execute_with_retries(req)
+            .map_err(unreachable)?;
+        let status = resp.status();
+        if status.is_server_error() {
+            return Err(unreachable(FetchError {
+                kind: FailureKind::Status,
+                status: Some(status.as_u16()),
+                message: status.to_string(),
+            }));
         }
+        if !status.is_success() {
+            debug!("no robots.txt for {}: {}", origin, status);
+            return Ok(Robots::allow_all());
+        }
+        let robots = resp.text()
+            .map_err(|e| unreachable(e.into()))?;
+        Ok(Robots::parse(&robots, &self.crawl.user_agent))
+    }
+}
+
+/// Parses a `Retry-After` header, either seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_http_date(value)?.to_secs()?;
+    Some(Duration::from_secs(date.saturating_sub(now())))
+}
+
+/// A failed request, classified for the failure ledger
+#[derive(Debug)]
+pub struct FetchError {
+    pub kind: FailureKind,
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
     }
 }
 
+impl std::error::Error for FetchError { }
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> FetchError {
+        let message = error_chain(&e);
+        // reqwest doesn't expose what went wrong while connecting, but hyper
+        // describes it in the error chain.
+        let kind = if e.is_timeout() {
+            FailureKind::Timeout
+        } else if message.contains("dns error") {
+            FailureKind::Dns
+        } else if message.contains("error trying to connect") {
+            FailureKind::Connect
+        } else {
+            FailureKind::Other
+        };
+        FetchError {
+            kind,
+            status: e.status().map(|s| s.as_u16()),
+            message,
+        }
+    }
+}
+
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        let cause = e.to_string();
+        if !message.contains(&cause) {
+            message.push_str(": ");
+            message.push_str(&cause);
+        }
+        source = e.source();
+    }
+    message
+}
+
+fn conditional(mut req: RequestBuilder, meta: &CacheMeta) -> RequestBuilder {
+    if let Some(etag) = &meta.etag {
+        req = req.header(header::IF_NONE_MATCH, etag.as_str());
+    }
+    if let Some(last_modified) = &meta.last_modified {
+        req = req.header(header::IF_MODIFIED_SINCE, last_modified.as_str());
+    }
+    req
+}
+
+fn header_string(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers.get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers.iter()
+        .map(|(name, value)| {
+            (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned())
+        })
+        .collect()
+}
+
+fn read_meta(path: &Path) -> Result<Option<CacheMeta>> {
+    match fs::read_to_string(path) {
+        Ok(meta) => {
+            let meta = toml::from_str(&meta)
+                .context("parsing cache metadata")?;
+            Ok(Some(meta))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("reading cache metadata"),
+    }
+}
+
+fn write_meta(path: &Path, meta: &CacheMeta) -> Result<()> {
+    let meta = toml::to_string(meta)
+        .context("serializing cache metadata")?;
+    write_atomic(path, meta)
+        .context("writing cache metadata")
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Limits the number of concurrent requests to each host, and how soon
+/// one request to a host follows another
+struct HostLimiter {
+    max_per_host: usize,
+    delay: Duration,
+    hosts: Mutex<HashMap<String, HostState>>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct HostState {
+    active: usize,
+    last_request: Option<Instant>,
+}
+
+/// Permission to make a request, released on drop
+struct HostPermit<'a> {
+    limiter: &'a HostLimiter,
+    host: String,
+}
+
+impl HostLimiter {
+    fn new(max_per_host: usize, delay: Duration) -> HostLimiter {
+        HostLimiter {
+            max_per_host: max_per_host.max(1),
+            delay,
+            hosts: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a request to the URL's host is allowed
+    fn acquire(&self, url: &Url) -> HostPermit<'_> {
+        let host = url.host_str().unwrap_or("").to_string();
+        let mut hosts = self.hosts.lock().expect("poisoned");
+        loop {
+            let state = hosts.entry(host.clone()).or_default();
+            if state.active >= self.max_per_host {
+                debug!("waiting for a request slot for {}", host);
+                hosts = self.released.wait(hosts).expect("poisoned");
+                continue;
+            }
+            let next_request = state.last_request.map(|last| last + self.delay);
+            match next_request {
+                Some(next_request) if next_request > Instant::now() => {
+                    debug!("waiting to make another request to {}", host);
+                    let wait = next_request.saturating_duration_since(Instant::now());
+                    hosts = self.released.wait_timeout(hosts, wait).expect("poisoned").0;
+                }
+                _ => {
+                    state.active += 1;
+                    state.last_request = Some(Instant::now());
+                    return HostPermit { limiter: self, host };
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Drop for HostPermit<'a> {
+    fn drop(&mut self) {
+        let mut hosts = self.limiter.hosts.lock().expect("poisoned");
+        if let Some(state) = hosts.get_mut(&self.host) {
+            state.active -= 1;
+        }
+        self.limiter.released.notify_all();
+    }
+}
+
+/// Writes a file by way of a temporary file in the same directory, so that
+/// readers on other threads see the old contents or the new, never part of
+/// the new
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let file_name = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.{}.{}.tmp", file_name, process::id(),
+                                          COUNTER.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e
+    })
+}
+
+/// Whether a file name is a hash as produced by `content_hash`
+pub fn is_hash(name: &str) -> bool {
+    name.len() == 40 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
 pub fn url_hash(url: &Url) -> String {
+    content_hash(url.as_str().as_bytes())
+}
+
+pub fn content_hash(bytes: &[u8]) -> String {
     let mut hasher = Blake2b::new();
-    hasher.input(url.as_str());
+    hasher.input(bytes);
     let res = hasher.result();
     hex::encode(&res[..20])
 }
 
-fn fetch_url(client: &mut HttpClient, url: &Url) -> Result<String> {
-    let resp = client.get(url.clone()).send()?;
-    debug!("printing headers");
-    for (key, value) in resp.headers() {
-        debug!("{}: {:?}", key, value);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Serves one response per connection from `responses`, in order, and
+    /// returns the head of each request received
+    fn serve(responses: Vec<&'static str>) -> (Url, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/post", listener.local_addr().unwrap())).unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                requests.push(head.to_ascii_lowercase());
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    fn cache(dir: &Path, refresh: bool) -> HttpCache {
+        cache_with_policy(dir, CachePolicy { refresh, ..CachePolicy::default() }, true)
+    }
+
+    fn cache_with_policy(dir: &Path, policy: CachePolicy, ignore_robots: bool) -> HttpCache {
+        let crawl = CrawlPolicy {
+            user_agent: "test".to_string(),
+            max_per_host: 1,
+            delay: Duration::from_secs(0),
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(5),
+            retries: 0,
+            ignore_robots,
+        };
+        HttpCache::new(dir.to_path_buf(), policy, crawl).unwrap()
+    }
+
+    /// An empty directory for a test's cache
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("http-cache-test-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn revalidates_with_etag_and_last_modified() {
+        let dir = temp_dir("revalidate");
+
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/html\r\n\
+             ETag: \"v1\"\r\n\
+             Last-Modified: Sat, 01 Aug 2020 10:00:00 GMT\r\n\
+             Content-Length: 5\r\n\
+             Connection: close\r\n\r\n\
+             hello",
+            "HTTP/1.1 304 Not Modified\r\n\
+             Connection: close\r\n\r\n",
+        ]);
+        let page = cache(&dir, false).get(&url).unwrap();
+        assert_eq!(page.body, b"hello");
+        let page = cache(&dir, true).get(&url).unwrap();
+        assert_eq!(page.body, b"hello");
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\"\r\n"));
+        assert!(requests[1].contains("if-modified-since: sat, 01 aug 2020 10:00:00 gmt\r\n"));
+        let meta = cache(&dir, false).meta(&url).unwrap().unwrap();
+        assert_eq!(meta.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(meta.last_modified.as_deref(), Some("Sat, 01 Aug 2020 10:00:00 GMT"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retries_server_errors_on_the_next_run() {
+        let dir = temp_dir("server-error");
+        let (url, server) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/html\r\n\
+             Content-Length: 5\r\n\
+             Connection: close\r\n\r\n\
+             hello",
+        ]);
+        assert!(cache(&dir, false).get(&url).is_err());
+        let cache = cache(&dir, false);
+        assert_eq!(cache.failures().get(&url).unwrap().status, Some(503));
+        assert_eq!(cache.status(&url).unwrap(), EntryStatus::Missing);
+
+        let page = cache.get(&url).unwrap();
+        assert_eq!(page.body, b"hello");
+        assert!(cache.failures().get(&url).is_none());
+        assert_eq!(server.join().unwrap().len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_gone_urls_on_the_next_run() {
+        let dir = temp_dir("gone");
+        let (url, server) = serve(vec![
+            "HTTP/1.1 404 Not Found\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+        ]);
+        assert!(cache(&dir, false).get(&url).is_err());
+        assert_eq!(server.join().unwrap().len(), 1);
+
+        // The server is gone, so this would fail to connect if it was tried
+        let cache = cache(&dir, false);
+        assert_eq!(cache.status(&url).unwrap(), EntryStatus::Failed);
+        let error = match cache.get(&url) {
+            Ok(_) => panic!("fetched a known-dead url"),
+            Err(e) => format!("{:#}", e),
+        };
+        assert!(error.contains("known-dead"), "{}", error);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn revalidates_after_a_failed_revalidation() {
+        let dir = temp_dir("failed-revalidation");
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/html\r\n\
+             Content-Length: 5\r\n\
+             Connection: close\r\n\r\n\
+             hello",
+            "HTTP/1.1 503 Service Unavailable\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/html\r\n\
+             Content-Length: 5\r\n\
+             Connection: close\r\n\r\n\
+             world",
+        ]);
+        cache(&dir, false).get(&url).unwrap();
+        // The stale copy is served while the server is down
+        assert_eq!(cache(&dir, true).get(&url).unwrap().body, b"hello");
+        assert_eq!(cache(&dir, true).get(&url).unwrap().body, b"world");
+        assert_eq!(server.join().unwrap().len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-    if resp.status().is_success() {
-        Ok(resp.text()
-            .context("parsing response as text")?)
-    } else {
-        Err(anyhow!("failed to fetch url {}", url))
+
+    #[test]
+    fn falls_back_to_archived_snapshots() {
+        let dir = temp_dir("archive");
+        // Nothing listens on the origin's port once the listener is dropped
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}/post", listener.local_addr().unwrap())).unwrap()
+        };
+        let (archive_url, archive) = serve(vec![
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/html\r\n\
+             Connection: close\r\n\r\n\
+             <html><body>\
+             <!-- BEGIN WAYBACK TOOLBAR INSERT --><div>toolbar</div><!-- END WAYBACK TOOLBAR INSERT -->\
+             <p><a href=\"/web/20200102030405/https://example.com/a\">link</a></p>\
+             </body></html>",
+        ]);
+        let policy = CachePolicy {
+            archive: Some(archive_url.join("/").unwrap()),
+            ..CachePolicy::default()
+        };
+
+        let page = cache_with_policy(&dir, policy, true).get(&url).unwrap();
+        let requests = archive.join().unwrap();
+        assert!(requests[0].starts_with("get /web/"), "{}", requests[0]);
+        assert!(requests[0].contains(&format!("/{} http/1.1", url)), "{}", requests[0]);
+
+        let snapshot = page.snapshot.clone().expect("not served from the archive");
+        assert_eq!(snapshot.host_str(), archive_url.host_str());
+        assert!(archive::snapshot(&snapshot).captured_at.is_some());
+        let text = page.text();
+        assert!(!text.contains("toolbar"));
+        assert!(text.contains("<a href=\"https://example.com/a\">"));
+
+        // The snapshot is cached under the original URL
+        let meta = cache(&dir, false).meta(&url).unwrap().unwrap();
+        assert_eq!(meta.snapshot, Some(snapshot));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreachable_robots_txt_disallows_all() {
+        let dir = temp_dir("robots");
+        let (url, server) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/html\r\n\
+             Content-Length: 5\r\n\
+             Connection: close\r\n\r\n\
+             hello",
+        ]);
+        let error = match cache_with_policy(&dir, CachePolicy::default(), false).get(&url) {
+            Ok(_) => panic!("fetched a url with an unreachable robots.txt"),
+            Err(e) => format!("{:#}", e),
+        };
+        assert!(error.contains("robots.txt for http://127.0.0.1:"), "{}", error);
+        assert!(error.contains("is unreachable"), "{}", error);
+
+        // A missing robots.txt allows everything, and the failure above
+        // doesn't make the url dead
+        let page = cache_with_policy(&dir, CachePolicy::default(), false).get(&url).unwrap();
+        assert_eq!(page.body, b"hello");
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("get /robots.txt "));
+        assert!(requests[1].starts_with("get /robots.txt "));
+        assert!(requests[2].starts_with("get /post "));
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,151 @@
+use log::{info, warn, debug};
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, Context};
+use url::Url;
+use crate::doc::*;
+use crate::http_cache::{self, HttpCache};
+
+/// Downloads every image referenced by the document into `img_dir` and
+/// rewrites the image sources to point at the local copies, prefixed by
+/// `img_href`. Images are named by the hash of their contents, so the same
+/// image used by multiple posts is only stored once.
+///
+/// Images that can't be downloaded are left pointing at their origin.
+pub fn mirror(client: &HttpCache, doc: &mut Document, img_dir: &Path, img_href: &str) {
+    let mut mirror = Mirror {
+        client, img_dir, img_href,
+    };
+
+    mirror_blocks(&mut mirror, &mut doc.body.blocks);
+}
+
+struct Mirror<'a> {
+    client: &'a HttpCache,
+    img_dir: &'a Path,
+    img_href: &'a str,
+}
+
+fn mirror_blocks(m: &mut Mirror, blocks: &mut Vec<Block>) {
+    for block in blocks {
+        match block {
+            Block::Heading(Heading { inlines, .. }) |
+            Block::Paragraph(Paragraph { inlines }) |
+            Block::CodeBlock(CodeBlock { inlines, .. }) => {
+                mirror_inlines(m, inlines);
+            }
+            Block::List(list) => {
+                for item in &mut list.items {
+                    mirror_blocks(m, &mut item.blocks);
+                }
+            }
+            Block::Blockquote(Blockquote { blocks }) => {
+                mirror_blocks(m, blocks);
+            }
+            Block::Image(image) => {
+                mirror_image(m, image);
+            }
+            Block::Figure(figure) => {
+                mirror_image(m, &mut figure.image);
+                mirror_inlines(m, &mut figure.caption);
+            }
+            Block::Table(table) => {
+                mirror_inlines(m, &mut table.caption);
+                for row in table.header.iter_mut().chain(table.body.iter_mut()) {
+                    for cell in &mut row.cells {
+                        mirror_inlines(m, &mut cell.inlines);
+                    }
+                }
+            }
+            Block::ThematicBreak => { }
+        }
+    }
+}
+
+fn mirror_inlines(m: &mut Mirror, inlines: &mut Vec<Inline>) {
+    for inline in inlines {
+        match inline {
+            Inline::Text(_) => { }
+            Inline::Bold(inlines) |
+            Inline::Italic(inlines) |
+            Inline::Code(inlines) => {
+                mirror_inlines(m, inlines);
+            }
+            Inline::Link(link) => {
+                mirror_inlines(m, &mut link.inlines);
+            }
+            Inline::Image(image) => {
+                mirror_image(m, image);
+            }
+        }
+    }
+}
+
+fn mirror_image(m: &mut Mirror, image: &mut Image) {
+    match mirror_image_(m, &image.src) {
+        Ok(Some(file_name)) => {
+            image.src = format!("{}{}", m.img_href, file_name);
+        }
+        Ok(None) => {
+            debug!("not mirroring {}", image.src);
+        }
+        Err(e) => {
+            warn!("unable to mirror image {}: {:#}", image.src, e);
+        }
+    }
+}
+
+fn mirror_image_(m: &mut Mirror, src: &str) -> Result<Option<String>> {
+    let url = Url::parse(src)
+        .context("parsing image url")?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Ok(None);
+    }
+
+    let bytes = m.client.get_bytes(&url)?;
+    let ext = sniff_extension(&bytes)
+        .or_else(|| url_extension(&url))
+        .unwrap_or("img");
+    let file_name = format!("{}.{}", http_cache::content_hash(&bytes), ext);
+    let img_file = m.img_dir.join(&file_name);
+
+    // Posts that share an image may race to write it, but as the name is
+    // its hash they write the same contents
+    if !img_file.exists() {
+        fs::create_dir_all(m.img_dir)
+            .context("creating image dir")?;
+        http_cache::write_atomic(&img_file, &bytes)
+            .context("writing image")?;
+        info!("mirrored {} to {}", url, img_file.display());
+    }
+
+    Ok(Some(file_name))
+}
+
+fn sniff_extension(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG") {
+        Some("png")
+    } else if bytes.starts_with(b"\xFF\xD8\xFF") {
+        Some("jpg")
+    } else if bytes.starts_with(b"GIF8") {
+        Some("gif")
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+fn url_extension(url: &Url) -> Option<&'static str> {
+    let path = url.path().to_lowercase();
+    let ext = path.rsplit('.').next()?;
+    match ext {
+        "png" => Some("png"),
+        "jpg" | "jpeg" => Some("jpg"),
+        "gif" => Some("gif"),
+        "webp" => Some("webp"),
+        "svg" => Some("svg"),
+        "avif" => Some("avif"),
+        _ => None,
+    }
+}
@@ -12,11 +12,18 @@ use crate::assets::{RESET_CSS_FILE, MAIN_CSS_FILE, BLOG_CSS_FILE};
 use crate::render;
 use crate::config::BlogPost;
 use crate::author::AuthorMaps;
+use crate::date::DateTime;
+use v_htmlescape::escape;
 
+#[derive(Clone)]
 pub struct IndexEntry {
     pub post_meta: BlogPost,
     pub title: String,
     pub file_name: String,
+    /// From the page's metadata
+    pub published: Option<DateTime>,
+    /// From the page's metadata, for posts whose author isn't configured
+    pub author: Option<String>,
 }
 
 static TITLE: &'static str = "The Rust Docuverse";
@@ -32,6 +39,7 @@ pub fn write(dir: &Path, assets: &AssetDirs, data: Vec<IndexEntry>, authors: Aut
 
     let header_meta = render::HeaderMeta {
         title: Some("The Rust Docuniverse".to_string()),
+        ..Default::default()
     };
         
     render::render_head(&mut file, assets, &header_meta);
@@ -62,6 +70,7 @@ fn write_body(file: &mut File, entries: Vec<IndexEntry>, authors: AuthorMaps) ->
             writeln!(file, "<a href='./p/{}.html'>{}</a>",
                      file_name, title);
             maybe_write_author(file, &entry, &authors)?;
+            maybe_write_date(file, &entry)?;
             writeln!(file, "</p>");
         }
         writeln!(file, "</section>");
@@ -79,6 +88,15 @@ fn maybe_write_author(file: &mut File, entry: &IndexEntry, authors: &AuthorMaps)
         let link = format!("./a/{}.html", name_slug);
         writeln!(file, "by <a href='{}'>{}</a>", link, name);
         writeln!(file, "</span>");
+    } else if let Some(name) = &entry.author {
+        writeln!(file, "<span>by {}</span>", escape(name));
+    }
+    Ok(())
+}
+
+fn maybe_write_date(file: &mut File, entry: &IndexEntry) -> Result<()> {
+    if let Some(date) = &entry.published {
+        writeln!(file, "<time datetime='{}'>{}</time>", date.iso8601(), date.iso8601_date());
     }
     Ok(())
 }
@@ -1,6 +1,6 @@
 #![allow(unused)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::cell::RefCell;
 use std::fs;
 use reqwest::StatusCode;
@@ -9,13 +9,17 @@ use regex::Regex;
 use std::io::Write;
 use url::Url;
 use serde::Deserialize;
-use log::{info, debug, error};
+use log::{info, debug, warn, error};
 use anyhow::{Result, Context, bail, anyhow};
 use structopt::StructOpt;
 use std::path::PathBuf;
-use crate::http_cache::HttpCache;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::http_cache::{HttpCache, CachePolicy, CrawlPolicy, NotCached, EntryStatus, Page};
 use crate::config::{load_config, Config, BlogPost};
 use crate::index::IndexEntry;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::selector::Selector;
+use crate::date::DateTime;
 
 mod http_cache;
 mod html;
@@ -28,6 +32,22 @@ mod config;
 mod extract;
 mod index;
 mod author;
+mod images;
+mod highlight;
+mod report;
+mod manifest;
+mod pool;
+mod failures;
+mod charset;
+mod cache_admin;
+mod date;
+mod warc;
+mod archive;
+mod robots;
+mod selector;
+mod score;
+mod boilerplate;
+mod metadata;
 
 #[derive(StructOpt, Debug)]
 struct Opts {
@@ -41,16 +61,24 @@ struct Opts {
 enum Command {
     DumpConfig,
     Fetch(FetchCmd),
+    FetchFailures(FetchFailures),
+    CacheStatus(CacheStatus),
+    Cache(CacheCmd),
     WalkTags(WalkTagsCmd),
+    Select(Select),
     ExtractArticle(ExtractArticle),
     ConvertArticle(ConvertArticle),
+    ConversionReport(ConversionReport),
+    CanonicalUrls(CanonicalUrls),
     RenderArticle(RenderArticle),
     CopyAssets(CopyAssets),
     ExtractTitle(ExtractTitle),
+    ShowMeta(ShowMeta),
     GenerateSlug(GenerateSlug),
     WriteIndex(WriteIndex),
     CreateAuthorMaps(CreateAuthorMaps),
     WriteAuthorPages(WriteAuthorPages),
+    Build(Build),
 }
 
 #[derive(StructOpt, Debug)]
@@ -58,11 +86,72 @@ struct FetchCmd {
     url_regex: String,
 }
 
+/// Lists URLs that failed to fetch
+#[derive(StructOpt, Debug)]
+struct FetchFailures {
+    /// Include images and other URLs that aren't blog posts
+    #[structopt(long)]
+    all: bool,
+}
+
+/// Lists which posts are cached, and which would be fetched
+#[derive(StructOpt, Debug)]
+struct CacheStatus {
+    #[structopt(default_value = ".")]
+    url_regex: String,
+}
+
+/// Inspects and maintains the HTTP cache
+#[derive(StructOpt, Debug)]
+enum CacheCmd {
+    /// Lists cached URLs with their size and fetch date
+    Ls,
+    /// Checks that every post is cached and no entry is corrupt
+    Verify,
+    /// Removes pages that aren't configured posts
+    Prune {
+        #[structopt(long)]
+        dry_run: bool,
+        /// Also remove entries without metadata, which may be pages or
+        /// images of configured posts if their URL isn't known
+        #[structopt(long)]
+        include_legacy: bool,
+    },
+    /// Writes the cache to a .tar.gz file
+    Export {
+        file: PathBuf,
+    },
+    /// Adds the entries of an exported .tar.gz file to the cache
+    Import {
+        file: PathBuf,
+    },
+    /// Writes the cache to a WARC file, gzipped if it ends in .gz
+    ExportWarc {
+        file: PathBuf,
+    },
+    /// Adds the responses in a WARC file to the cache
+    ImportWarc {
+        file: PathBuf,
+    },
+}
+
 #[derive(StructOpt, Debug)]
 struct WalkTagsCmd {
     url_regex: String,
 }
 
+/// Prints the elements of each post's page that match a CSS selector, for
+/// writing rules in sites.toml
+#[derive(StructOpt, Debug)]
+struct Select {
+    selector: String,
+    #[structopt(default_value = ".")]
+    url_regex: String,
+    /// Print the HTML of each match, instead of a summary
+    #[structopt(long)]
+    html: bool,
+}
+
 #[derive(StructOpt, Debug)]
 struct ExtractArticle {
     url_regex: String,
@@ -73,6 +162,27 @@ struct ConvertArticle {
     url_regex: String,
 }
 
+#[derive(StructOpt, Debug)]
+struct ConversionReport {
+    url_regex: String,
+}
+
+/// Reports posts whose configured URL redirects elsewhere, or whose page
+/// names a different canonical URL. Redirects are only known for pages
+/// fetched with redirect tracking, so use `--refresh` on an older cache.
+#[derive(StructOpt, Debug)]
+struct CanonicalUrls {
+    #[structopt(default_value = ".")]
+    url_regex: String,
+    /// Rewrite blog-posts.toml to use the canonical URLs
+    #[structopt(long)]
+    rewrite: bool,
+    /// Also rewrite URLs whose new blog prefix in authors.toml belongs to a
+    /// different author, or to none
+    #[structopt(long)]
+    change_authors: bool,
+}
+
 #[derive(StructOpt, Debug)]
 struct RenderArticle {
     url_regex: String,
@@ -88,6 +198,13 @@ struct ExtractTitle {
     url_regex: String,
 }
 
+/// Shows the dates, author and other metadata each post's page declares
+#[derive(StructOpt, Debug)]
+struct ShowMeta {
+    #[structopt(default_value = ".")]
+    url_regex: String,
+}
+
 #[derive(StructOpt, Debug)]
 struct GenerateSlug {
     url_regex: String,
@@ -107,6 +224,16 @@ struct WriteAuthorPages {
     url_regex: String,
 }
 
+/// Renders the whole site: posts, index, author pages and assets
+#[derive(StructOpt, Debug)]
+struct Build {
+    #[structopt(default_value = ".")]
+    url_regex: String,
+    /// Rebuild every post, even those that are up to date
+    #[structopt(long)]
+    force: bool,
+}
+
 #[derive(StructOpt, Debug)]
 struct GlobalOpts {
     #[structopt(default_value = "./data")]
@@ -114,10 +241,72 @@ struct GlobalOpts {
     /// Process posts that aren't marked for publication
     #[structopt(long)]
     unpublished: bool,
+    /// Number of posts to process in parallel [default: available parallelism]
+    #[structopt(short, long)]
+    jobs: Option<usize>,
+    /// Maximum number of concurrent requests to a single host
+    #[structopt(long, default_value = "2")]
+    jobs_per_host: usize,
+    /// The user agent to identify as
+    #[structopt(long, default_value = DEFAULT_USER_AGENT)]
+    user_agent: String,
+    /// Minimum time between requests to a single host
+    #[structopt(long, default_value = "1s", parse(try_from_str = parse_duration))]
+    delay: Duration,
+    #[structopt(long, default_value = "10s", parse(try_from_str = parse_duration))]
+    connect_timeout: Duration,
+    /// Time allowed for a whole request
+    #[structopt(long, default_value = "60s", parse(try_from_str = parse_duration))]
+    timeout: Duration,
+    /// Number of times to retry requests that get a 429 or 5xx response
+    #[structopt(long, default_value = "3")]
+    retries: u32,
+    /// Fetch URLs even if robots.txt disallows them
+    #[structopt(long)]
+    ignore_robots: bool,
+    /// Revalidate cached pages with the server
+    #[structopt(long)]
+    refresh: bool,
+    /// Revalidate cached pages fetched longer ago than this, e.g. 90s, 30m,
+    /// 12h or 7d
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    max_age: Option<Duration>,
+    /// Fetch URLs that failed to fetch before
+    #[structopt(long)]
+    retry_failed: bool,
+    /// Never use the network. Pages that aren't cached fail to process.
+    #[structopt(long)]
+    offline: bool,
+    /// Use archived snapshots of URLs that can't be fetched
+    #[structopt(long)]
+    archive_fallback: bool,
+    /// The Wayback-style archive to get snapshots from
+    #[structopt(long, default_value = archive::DEFAULT_ENDPOINT)]
+    archive_url: Url,
+}
+
+fn parse_duration(s: &str) -> Result<Duration> {
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num: u64 = num.parse()
+        .with_context(|| format!("invalid duration '{}'", s))?;
+    let unit_secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => bail!("invalid duration unit '{}'", unit),
+    };
+    let secs = num.checked_mul(unit_secs)
+        .ok_or_else(|| anyhow!("duration '{}' is too long", s))?;
+    Ok(Duration::from_secs(secs))
 }
 
+static DEFAULT_USER_AGENT: &'static str = concat!("rust-doc-tool/", env!("CARGO_PKG_VERSION"));
 static RENDER_DIR: &'static str = "render";
 static POST_DIR: &'static str = "p";
+static IMG_DIR: &'static str = "img";
+static HTTP_CACHE_DIR: &'static str = "http-cache";
+static BUILD_MANIFEST_FILE: &'static str = "build-manifest.toml";
 
 struct CmdOpts<T> {
     global_opts: GlobalOpts,
@@ -147,15 +336,33 @@ fn main() -> Result<()> {
         Command::Fetch(cmd) => {
             run_fetch(CmdOpts { global_opts, config, cmd })
         }
+        Command::FetchFailures(cmd) => {
+            run_fetch_failures(CmdOpts { global_opts, config, cmd })
+        }
+        Command::CacheStatus(cmd) => {
+            run_cache_status(CmdOpts { global_opts, config, cmd })
+        }
+        Command::Cache(cmd) => {
+            run_cache(CmdOpts { global_opts, config, cmd })
+        }
         Command::WalkTags(cmd) => {
             run_walk_tags(CmdOpts { global_opts, config, cmd })
         }
+        Command::Select(cmd) => {
+            run_select(CmdOpts { global_opts, config, cmd })
+        }
         Command::ExtractArticle(cmd) => {
             run_extract_article(CmdOpts { global_opts, config, cmd })
         }
         Command::ConvertArticle(cmd) => {
             run_convert_article(CmdOpts { global_opts, config, cmd })
         }
+        Command::ConversionReport(cmd) => {
+            run_conversion_report(CmdOpts { global_opts, config, cmd })
+        }
+        Command::CanonicalUrls(cmd) => {
+            run_canonical_urls(CmdOpts { global_opts, config, cmd })
+        }
         Command::RenderArticle(cmd) => {
             run_render_article(CmdOpts { global_opts, config, cmd })
         }
@@ -165,6 +372,9 @@ fn main() -> Result<()> {
         Command::ExtractTitle(cmd) => {
             run_extract_title(CmdOpts { global_opts, config, cmd })
         }
+        Command::ShowMeta(cmd) => {
+            run_show_meta(CmdOpts { global_opts, config, cmd })
+        }
         Command::GenerateSlug(cmd) => {
             run_generate_slug(CmdOpts { global_opts, config, cmd })
         }
@@ -177,122 +387,435 @@ fn main() -> Result<()> {
         Command::WriteAuthorPages(cmd) => {
             run_write_author_pages(CmdOpts { global_opts, config, cmd })
         }
+        Command::Build(cmd) => {
+            run_build(CmdOpts { global_opts, config, cmd })
+        }
     }
 }
 
 fn run_fetch(cmd: CmdOpts<FetchCmd>) -> Result<()> {
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|_, post| {
-        debug!("{}", post);
+    let client = http_cache(&cmd.global_opts)?;
+    for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|_, page| {
+        debug!("{}", page.text());
         Ok(())
-    })
+    })?;
+    Ok(())
 }
 
-type PostHandler<'a> = dyn Fn(&BlogPost, String) -> Result<()> + 'a;
+fn run_fetch_failures(cmd: CmdOpts<FetchFailures>) -> Result<()> {
+    let client = http_cache(&cmd.global_opts)?;
+    let posts: HashSet<&str> = cmd.config.blog_posts.iter()
+        .map(|post| post.url.as_str())
+        .collect();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
-fn for_each_post(opts: &GlobalOpts, config: &Config, url_regex: &str, f: &PostHandler) -> Result<()> {
-    let regex = Regex::new(url_regex)
+    let failures: Vec<_> = client.failures().failures().into_iter()
+        .filter(|(url, _)| cmd.cmd.all || posts.contains(url.as_str()))
+        .collect();
+
+    info!("{:>8} {:>8} {:>8}  {}", "attempts", "last", "error", "url");
+    for (url, failure) in &failures {
+        let error = match failure.status {
+            Some(status) => status.to_string(),
+            None => failure.kind.to_string(),
+        };
+        let age = format_age(now.saturating_sub(failure.last_failed));
+        info!("{:>8} {:>8} {:>8}  {}", failure.attempts, age, error, url);
+        debug!("{:>27}  {}", "", failure.error);
+    }
+    info!("{} failed urls", failures.len());
+
+    Ok(())
+}
+
+fn run_cache_status(cmd: CmdOpts<CacheStatus>) -> Result<()> {
+    let client = http_cache(&cmd.global_opts)?;
+    let regex = Regex::new(&cmd.cmd.url_regex)
         .context("building regex")?;
-    let cache_dir = opts.data_dir.join("http-cache");
-    let mut client = HttpCache::new(cache_dir);
+    let mut counts = BTreeMap::new();
 
-    for post in &config.blog_posts {
-        let publish = post.publish || opts.unpublished;
-        if !publish {
-            debug!("skipping {}", post.url);
+    for post in &cmd.config.blog_posts {
+        let publish = post.publish || cmd.global_opts.unpublished;
+        if !publish || !regex.is_match(post.url.as_str()) {
             continue;
         }
-        if regex.is_match(&post.url.as_str()) {
-            info!("fetching {}", post.url);
-            let page = client.get(&post.url);
-            match page {
-                Ok(page) => {
-                    f(post, page)?;
-                }
-                Err(e) => {
-                    error!("error: {}", e);
-                }
-            }
-        }
+        let status = client.status(&post.url)?;
+        *counts.entry(status.to_string()).or_insert(0) += 1;
+        info!("{:>8}  {}", status, post.url);
     }
-    
+
+    let counts: Vec<String> = counts.iter()
+        .map(|(status, count)| format!("{} {}", count, status))
+        .collect();
+    info!("{}", counts.join(", "));
     Ok(())
 }
 
-fn run_walk_tags(cmd: CmdOpts<WalkTagsCmd>) -> Result<()> {
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|_, post| {
-        html::walk_tags(&post)?;
-        Ok(())
-    })
+fn run_cache(cmd: CmdOpts<CacheCmd>) -> Result<()> {
+    let client = http_cache(&cmd.global_opts)?;
+
+    match &cmd.cmd {
+        CacheCmd::Ls => {
+            cache_admin::ls(&client)
+        }
+        CacheCmd::Verify => {
+            let posts: Vec<&BlogPost> = cmd.config.blog_posts.iter()
+                .filter(|post| post.publish || cmd.global_opts.unpublished)
+                .collect();
+            cache_admin::verify(&client, &posts)
+        }
+        CacheCmd::Prune { dry_run, include_legacy } => {
+            cache_admin::prune(&client, &cmd.config.blog_posts, *dry_run, *include_legacy)
+        }
+        CacheCmd::Export { file } => {
+            cache_admin::export(&client, file)
+        }
+        CacheCmd::Import { file } => {
+            cache_admin::import(&client, file)
+        }
+        CacheCmd::ExportWarc { file } => {
+            warc::export(&client, file)
+        }
+        CacheCmd::ImportWarc { file } => {
+            warc::import(&client, file)
+        }
+    }
 }
 
-fn run_extract_article(cmd: CmdOpts<ExtractArticle>) -> Result<()> {
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|_, post| {
-        match html::extract_article_string(&post) {
-            Ok(s) => {
-                info!("{}", s);
+fn format_age(secs: u64) -> String {
+    match secs {
+        s if s < 60 * 60 => format!("{}m ago", s / 60),
+        s if s < 60 * 60 * 24 => format!("{}h ago", s / (60 * 60)),
+        s => format!("{}d ago", s / (60 * 60 * 24)),
+    }
+}
+
+type PostHandler<'a, T> = dyn Fn(&BlogPost, &Page) -> Result<T> + Sync + 'a;
+
+/// Fetches each matching post and runs `f` on it, on a pool of `--jobs`
+/// threads. Returns the results of the posts that succeeded in config
+/// order, and the number of posts that failed to fetch or process, which are
+/// logged, also in config order.
+fn for_each_post<T: Send>(opts: &GlobalOpts, config: &Config, client: &HttpCache,
+                          url_regex: &str, f: &PostHandler<T>) -> Result<(Vec<T>, usize)> {
+    let regex = Regex::new(url_regex)
+        .context("building regex")?;
+
+    let posts: Vec<&BlogPost> = config.blog_posts.iter()
+        .filter(|post| {
+            let publish = post.publish || opts.unpublished;
+            if !publish {
+                debug!("skipping {}", post.url);
+            }
+            publish && regex.is_match(&post.url.as_str())
+        })
+        .collect();
+
+    let results = pool::map(jobs(opts), &posts, |post| {
+        info!("fetching {}", post.url);
+        let page = client.get(&post.url)
+            .context("fetching post")?;
+        f(post, &page)
+    });
+
+    let mut values = Vec::new();
+    let mut failures = 0;
+    let mut not_cached = 0;
+    for (post, result) in posts.iter().zip(results) {
+        match result {
+            Ok(value) => {
+                values.push(value);
+            }
+            Err(e) if e.downcast_ref::<NotCached>().is_some() => {
+                error!("not cached: {}", post.url);
+                failures += 1;
+                not_cached += 1;
             }
             Err(e) => {
-                error!("{}", e);
+                error!("error processing {}: {:#}", post.url, e);
+                failures += 1;
             }
         }
+    }
+    if not_cached > 0 {
+        error!("{} posts are not cached; fetch them before working offline", not_cached);
+    }
+    
+    Ok((values, failures))
+}
+
+/// The extraction rules for a post, from `sites.toml`
+fn extract_rules(config: &Config, post: &BlogPost) -> Result<html::ExtractRules> {
+    html::ExtractRules::from_site(config::site_for(&config.sites, &post.url))?
+        .with_post(post)
+}
+
+fn jobs(opts: &GlobalOpts) -> usize {
+    opts.jobs.unwrap_or_else(pool::default_jobs)
+}
+
+/// Converts the article, logging a summary of the conversion diagnostics.
+/// `text` is the decoded page the article was extracted from.
+fn convert_article(post: &BlogPost, page: &Page, text: &str, dom: &html::SubDom) -> Result<doc::Document> {
+    let (mut doc, diagnostics) = convert::from_dom(post, dom)
+        .context("converting article")?;
+    report_diagnostics(post, &diagnostics);
+    doc.meta.snapshot = page.snapshot.as_ref().map(archive::snapshot);
+    doc.meta.page = metadata::extract(text)
+        .context("extracting page metadata")?;
+    override_page_meta(post, &mut doc.meta.page);
+    Ok(doc)
+}
+
+/// Applies the post's config overrides to what the page says about it. The
+/// date was checked when the config was loaded.
+fn override_page_meta(post: &BlogPost, page: &mut doc::PageMeta) {
+    if let Some(date) = post.date.as_deref().and_then(DateTime::parse_iso8601) {
+        page.published = Some(date);
+    }
+    if let Some(author) = &post.author {
+        page.author = Some(author.clone());
+    }
+    if let Some(summary) = &post.summary {
+        page.description = Some(summary.clone());
+    }
+}
+
+fn report_diagnostics(post: &BlogPost, diagnostics: &[convert::Diagnostic]) {
+    // Skipped wrappers are in nearly every post and lose nothing, so they'd
+    // only bury the diagnostics that matter. `conversion-report` counts them.
+    let diagnostics: Vec<_> = diagnostics.iter()
+        .filter(|diagnostic| !diagnostic.kind.is_transparent())
+        .collect();
+    if diagnostics.is_empty() {
+        return;
+    }
+
+    let mut counts = BTreeMap::new();
+    for diagnostic in diagnostics {
+        *counts.entry(diagnostic.kind.summary()).or_insert(0) += 1;
+        debug!("{}", diagnostic);
+    }
+    let counts: Vec<String> = counts.iter()
+        .map(|(summary, count)| format!("{} {}", count, summary))
+        .collect();
+    info!("converted {}: {}", post.url, counts.join(", "));
+}
+
+fn http_cache(opts: &GlobalOpts) -> Result<HttpCache> {
+    let cache_dir = opts.data_dir.join(HTTP_CACHE_DIR);
+    let policy = CachePolicy {
+        refresh: opts.refresh,
+        max_age: opts.max_age,
+        retry_failed: opts.retry_failed,
+        offline: opts.offline,
+        archive: if opts.archive_fallback {
+            Some(opts.archive_url.clone())
+        } else {
+            None
+        },
+    };
+    let crawl = CrawlPolicy {
+        user_agent: opts.user_agent.clone(),
+        max_per_host: opts.jobs_per_host,
+        delay: opts.delay,
+        connect_timeout: opts.connect_timeout,
+        timeout: opts.timeout,
+        retries: opts.retries,
+        ignore_robots: opts.ignore_robots,
+    };
+    HttpCache::new(cache_dir, policy, crawl)
+}
+
+fn run_walk_tags(cmd: CmdOpts<WalkTagsCmd>) -> Result<()> {
+    let client = http_cache(&cmd.global_opts)?;
+    for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|_, page| {
+        html::walk_tags(&page.text())?;
         Ok(())
-    })
+    })?;
+    Ok(())
+}
+
+fn run_select(cmd: CmdOpts<Select>) -> Result<()> {
+    let selector = Selector::parse(&cmd.cmd.selector)?;
+    let client = http_cache(&cmd.global_opts)?;
+    let (posts, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let (_, document) = html::extract_dom(&page.text())?;
+        let matches = selector::select(&document, &selector).iter()
+            .map(|node| {
+                if cmd.cmd.html {
+                    html::outer_html(node)
+                } else {
+                    let mut text = html::text(node);
+                    if text.chars().count() > 60 {
+                        text = text.chars().take(60).collect::<String>() + "...";
+                    }
+                    Ok(format!("{}  {}", html::describe(node), text))
+                }
+            })
+            .collect::<Result<Vec<String>>>()?;
+        Ok((meta.url.clone(), matches))
+    })?;
+
+    let mut total = 0;
+    for (url, matches) in &posts {
+        info!("{}: {} matches", url, matches.len());
+        for m in matches {
+            info!("    {}", m);
+        }
+        total += matches.len();
+    }
+    info!("{} matches in {} posts", total, posts.len());
+    Ok(())
+}
+
+fn run_extract_article(cmd: CmdOpts<ExtractArticle>) -> Result<()> {
+    let client = http_cache(&cmd.global_opts)?;
+    let (articles, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let rules = extract_rules(&cmd.config, meta)?;
+        html::extract_article_string(&page.text(), &rules)
+    })?;
+
+    for article in articles {
+        info!("{}", article);
+    }
+    Ok(())
 }
 
 fn run_convert_article(cmd: CmdOpts<ConvertArticle>) -> Result<()> {
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|meta, post| {
-        match html::extract_article(&post) {
-            Ok((dom, type_)) => {
-                let doc = convert::from_dom(&meta, &dom);
-                info!("{:#?}", doc);
+    let client = http_cache(&cmd.global_opts)?;
+    let (docs, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let rules = extract_rules(&cmd.config, meta)?;
+        let text = page.text();
+        let (dom, _) = extract_article(meta, &rules, &text)?;
+        convert_article(&meta, page, &text, &dom)
+    })?;
+
+    for doc in docs {
+        info!("{:#?}", doc);
+    }
+    Ok(())
+}
+
+fn run_conversion_report(cmd: CmdOpts<ConversionReport>) -> Result<()> {
+    let client = http_cache(&cmd.global_opts)?;
+    let (reports, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let rules = extract_rules(&cmd.config, meta)?;
+        let (dom, type_) = extract_article(meta, &rules, &page.text())?;
+        let (doc, diagnostics) = convert::from_dom(&meta, &dom)
+            .context("converting article")?;
+        Ok(report::post_report(&meta.url, type_, &dom, &doc, &diagnostics))
+    })?;
+
+    report::print(&reports);
+    Ok(())
+}
+
+/// A configured post URL that isn't the canonical URL of the post
+struct UrlChange {
+    from: Url,
+    to: Url,
+    reason: &'static str,
+    redirects: Vec<http_cache::Redirect>,
+}
+
+fn run_canonical_urls(cmd: CmdOpts<CanonicalUrls>) -> Result<()> {
+    let client = http_cache(&cmd.global_opts)?;
+
+    let (changes, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let cache_meta = client.meta(&meta.url)?;
+        let redirects = cache_meta.as_ref()
+            .map(|m| m.redirects.clone())
+            .unwrap_or_default();
+        let final_url = cache_meta
+            .and_then(|m| m.final_url)
+            .unwrap_or_else(|| meta.url.clone());
+        let (to, reason) = match html::canonical_url(&page.text(), &final_url)? {
+            Some(canonical) => (canonical, "canonical link"),
+            None => (final_url, "redirect"),
+        };
+        if to == meta.url {
+            return Ok(None);
+        }
+        Ok(Some(UrlChange {
+            from: meta.url.clone(),
+            to, reason, redirects,
+        }))
+    })?;
+    let changes: Vec<UrlChange> = changes.into_iter().flatten().collect();
+
+    let configured: HashSet<&Url> = cmd.config.blog_posts.iter()
+        .map(|post| &post.url)
+        .collect();
+    let mut renames = BTreeMap::new();
+
+    for change in &changes {
+        info!("{}", change.from);
+        for redirect in &change.redirects {
+            debug!("    {} {}", redirect.status, redirect.url);
+        }
+        info!("    -> {} ({})", change.to, change.reason);
+
+        if configured.contains(&change.to) {
+            warn!("    {} is also configured as a post", change.to);
+            continue;
+        }
+        let from_author = author::url_author(&cmd.config.authors, &change.from);
+        let to_author = author::url_author(&cmd.config.authors, &change.to);
+        if from_author.map(|a| &a.name) != to_author.map(|a| &a.name) {
+            warn!("    author would change from {} to {}; update authors.toml",
+                  from_author.map(|a| a.name.as_str()).unwrap_or("none"),
+                  to_author.map(|a| a.name.as_str()).unwrap_or("none"));
+            if !cmd.cmd.change_authors {
+                warn!("    not rewriting without --change-authors");
+                continue;
             }
-            Err(e) => {
-                error!("{}", e);
+        }
+        renames.insert(change.from.clone(), change.to.clone());
+    }
+    info!("{} posts with non-canonical urls", changes.len());
+
+    if cmd.cmd.rewrite && !renames.is_empty() {
+        let count = config::rewrite_post_urls(&renames)?;
+        for (from, to) in &renames {
+            if let Err(e) = client.alias(from, to) {
+                warn!("unable to reuse cache entry for {}: {:#}", to, e);
             }
         }
-        Ok(())
-    })
+        info!("rewrote {} urls in blog-posts.toml", count);
+    }
+
+    Ok(())
 }
 
 fn run_render_article(cmd: CmdOpts<RenderArticle>) -> Result<()> {
     let assets = assets::AssetDirs {
         css_dir: PathBuf::from("../css/"),
     };
+    let client = http_cache(&cmd.global_opts)?;
     
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|meta, post| {
-        match html::extract_article(&post) {
-            Ok((dom, type_)) => {
-                let doc = convert::from_dom(&meta, &dom);
-                let doc = sanitize::sanitize(doc, &post, type_);
-                let title = extract::title(&doc);
-                let doc = render::to_string(&assets, &doc)?;
-                if !cmd.cmd.to_file {
-                    info!("{}", doc);
-                } else {
-                    match title {
-                        Some(title) => {
-                            let file_name = sanitize::title_to_slug(title);
-                            let render_dir = cmd.global_opts.data_dir.join(RENDER_DIR);
-                            let post_dir = render_dir.join(POST_DIR);
-                            let render_file = post_dir.join(format!("{}.html", file_name));
-                            fs::create_dir_all(&post_dir)
-                                .context("creating post dir")?;
-                            fs::write(&render_file, doc)
-                                .context("writing rendered doc")?;
-                            info!("rendered at {}", render_file.display());
-                        }
-                        None => {
-                            error!("unable to extract title");
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                error!("{}", e);
+    let (docs, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let rules = extract_rules(&cmd.config, meta)?;
+        if !cmd.cmd.to_file {
+            let post = page.text();
+            let (dom, type_) = extract_article(meta, &rules, &post)?;
+            let doc = convert_article(&meta, page, &post, &dom)?;
+            let mut doc = sanitize::sanitize(doc, &post, type_, rules.title.as_ref());
+            if let Some(title) = &meta.title {
+                doc = sanitize::set_title(doc, title);
             }
+            Ok(Some(render::to_string(&assets, &doc)?))
+        } else {
+            let mut post = process_post(meta, &rules, page)?;
+            write_post(&cmd.global_opts, &client, &assets, &mut post)?;
+            Ok(None)
         }
-        Ok(())
-    })
+    })?;
+
+    for doc in docs.into_iter().flatten() {
+        info!("{}", doc);
+    }
+    Ok(())
 }
 
 fn run_copy_assets(cmd: CmdOpts<CopyAssets>) -> Result<()> {
@@ -306,91 +829,63 @@ fn run_copy_assets(cmd: CmdOpts<CopyAssets>) -> Result<()> {
 }
 
 fn run_extract_title(cmd: CmdOpts<ExtractTitle>) -> Result<()> {
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|meta, post| {
-        match html::extract_article(&post) {
-            Ok((dom, type_)) => {
-                let doc = convert::from_dom(&meta, &dom);
-                let doc = sanitize::sanitize(doc, &post, type_);
-                let title = extract::title(&doc);
-                match title {
-                    Some(title) => {
-                        info!("title: {}", title);
-                    },
-                    None => {
-                        error!("no title found");
-                    }
-                }
-            }
-            Err(e) => {
-                error!("{}", e);
-            }
-        }
-        Ok(())
-    })
+    let client = http_cache(&cmd.global_opts)?;
+    let (titles, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let rules = extract_rules(&cmd.config, meta)?;
+        Ok(process_post(meta, &rules, page)?.title)
+    })?;
+
+    for title in titles {
+        info!("title: {}", title);
+    }
+    Ok(())
+}
+
+fn run_show_meta(cmd: CmdOpts<ShowMeta>) -> Result<()> {
+    let client = http_cache(&cmd.global_opts)?;
+    let (metas, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        Ok((meta.url.clone(), metadata::extract(&page.text())?))
+    })?;
+
+    let none = || "-".to_string();
+    for (url, meta) in metas {
+        info!("{}", url);
+        info!("    published:   {}", meta.published.map(|d| d.iso8601()).unwrap_or_else(none));
+        info!("    updated:     {}", meta.updated.map(|d| d.iso8601()).unwrap_or_else(none));
+        info!("    author:      {}", meta.author.unwrap_or_else(none));
+        info!("    site name:   {}", meta.site_name.unwrap_or_else(none));
+        info!("    description: {}", meta.description.unwrap_or_else(none));
+    }
+    Ok(())
 }
 
 fn run_generate_slug(cmd: CmdOpts<GenerateSlug>) -> Result<()> {
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|meta, post| {
-        match html::extract_article(&post) {
-            Ok((dom, type_)) => {
-                let doc = convert::from_dom(&meta, &dom);
-                let doc = sanitize::sanitize(doc, &post, type_);
-                let title = extract::title(&doc);
-                match title {
-                    Some(title) => {
-                        let file_name = sanitize::title_to_slug(title);
-                        info!("slug: {}", file_name);
-                    },
-                    None => {
-                        error!("no title found");
-                    }
-                }
-            }
-            Err(e) => {
-                error!("{}", e);
-            }
-        }
-        Ok(())
-    })
+    let client = http_cache(&cmd.global_opts)?;
+    let (slugs, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let rules = extract_rules(&cmd.config, meta)?;
+        Ok(process_post(meta, &rules, page)?.file_name)
+    })?;
+
+    for slug in slugs {
+        info!("slug: {}", slug);
+    }
+    Ok(())
 }
 
 fn run_write_index(cmd: CmdOpts<WriteIndex>) -> Result<()> {
     let assets = assets::AssetDirs {
         css_dir: PathBuf::from("./css/"),
     };
-    let data = RefCell::new(Vec::new());
-
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|meta, post| {
-        match html::extract_article(&post) {
-            Ok((dom, type_)) => {
-                let doc = convert::from_dom(&meta, &dom);
-                let doc = sanitize::sanitize(doc, &post, type_);
-                let title = extract::title(&doc);
-                match title {
-                    Some(title) => {
-                        let file_name = sanitize::title_to_slug(title.clone());
-                        let index_entry = IndexEntry {
-                            post_meta: meta.clone(),
-                            title,
-                            file_name,
-                        };
-                        data.borrow_mut().push(index_entry);
-                    },
-                    None => {
-                        error!("no title found");
-                    }
-                }
-            }
-            Err(e) => {
-                error!("{}", e);
-            }
-        }
-        Ok(())
-    });
+    let client = http_cache(&cmd.global_opts)?;
+
+    let (data, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let rules = extract_rules(&cmd.config, meta)?;
+        Ok(process_post(meta, &rules, page)?.index_entry())
+    })?;
 
     let render_dir = cmd.global_opts.data_dir.join(RENDER_DIR);
     let author_maps = crate::author::create_author_maps(&cmd.config)?;
-    index::write(&render_dir, &assets, data.into_inner(), author_maps)?;
+    index::write(&render_dir, &assets, data, author_maps)?;
     Ok(())
 }
 
@@ -404,39 +899,224 @@ fn run_write_author_pages(cmd: CmdOpts<WriteAuthorPages>) -> Result<()> {
     let assets = assets::AssetDirs {
         css_dir: PathBuf::from("../css/"),
     };
-    let index_data = RefCell::new(Vec::new());
-
-    for_each_post(&cmd.global_opts, &cmd.config, &cmd.cmd.url_regex, &|meta, post| {
-        match html::extract_article(&post) {
-            Ok((dom, type_)) => {
-                let doc = convert::from_dom(&meta, &dom);
-                let doc = sanitize::sanitize(doc, &post, type_);
-                let title = extract::title(&doc);
-                match title {
-                    Some(title) => {
-                        let file_name = sanitize::title_to_slug(title.clone());
-                        let index_entry = IndexEntry {
-                            post_meta: meta.clone(),
-                            title,
-                            file_name,
-                        };
-                        index_data.borrow_mut().push(index_entry);
-                    },
-                    None => {
-                        error!("no title found");
-                    }
-                }
+    let client = http_cache(&cmd.global_opts)?;
+
+    let (index_data, _) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let rules = extract_rules(&cmd.config, meta)?;
+        Ok(process_post(meta, &rules, page)?.index_entry())
+    })?;
+
+    let render_dir = cmd.global_opts.data_dir.join(RENDER_DIR);
+    let author_maps = crate::author::create_author_maps(&cmd.config)?;
+    author::write_pages(&render_dir, &cmd.config.authors, &assets, index_data, author_maps)?;
+
+    Ok(())
+}
+
+/// A post written by `build`, or left as is because it was up to date
+struct BuiltPost {
+    url: Url,
+    entry: ManifestEntry,
+    rebuilt: bool,
+}
+
+/// Processes and writes every post, in parallel, then writes the index,
+/// author pages and assets of the whole site. Posts whose inputs haven't
+/// changed since the last build are not processed again, and the rendered
+/// files of posts that are no longer published are deleted. Posts that fail
+/// are logged and left out of the site, and make the build fail at the end.
+fn run_build(cmd: CmdOpts<Build>) -> Result<()> {
+    let render_dir = cmd.global_opts.data_dir.join(RENDER_DIR);
+    let post_dir = render_dir.join(POST_DIR);
+    let manifest_file = cmd.global_opts.data_dir.join(BUILD_MANIFEST_FILE);
+    let old_manifest = Manifest::load(&manifest_file)?;
+    let post_assets = assets::AssetDirs {
+        css_dir: PathBuf::from("../css/"),
+    };
+    let client = http_cache(&cmd.global_opts)?;
+
+    let (built, failures) = for_each_post(&cmd.global_opts, &cmd.config, &client, &cmd.cmd.url_regex, &|meta, page| {
+        let site = config::site_for(&cmd.config.sites, &meta.url);
+        let inputs = manifest::Inputs::new(meta, site, &page.text())?;
+        if let Some(entry) = old_manifest.get(&meta.url) {
+            let render_file = post_dir.join(format!("{}.html", entry.file_name));
+            if !cmd.cmd.force && entry.inputs == inputs && render_file.exists() {
+                debug!("up to date: {}", meta.url);
+                return Ok(BuiltPost {
+                    url: meta.url.clone(),
+                    entry: entry.clone(),
+                    rebuilt: false,
+                });
             }
-            Err(e) => {
-                error!("{}", e);
+        }
+        let rules = extract_rules(&cmd.config, meta)?;
+        let mut post = process_post(meta, &rules, page)?;
+        write_post(&cmd.global_opts, &client, &post_assets, &mut post)?;
+        Ok(BuiltPost {
+            url: meta.url.clone(),
+            entry: ManifestEntry {
+                published: post.doc.meta.page.published.map(|date| date.iso8601()),
+                author: post.doc.meta.page.author.clone(),
+                title: post.title,
+                file_name: post.file_name,
+                inputs,
+            },
+            rebuilt: true,
+        })
+    })?;
+
+    let rebuilt = built.iter().filter(|post| post.rebuilt).count();
+    let up_to_date = built.len() - rebuilt;
+    let mut manifest = Manifest::default();
+    for post in built {
+        manifest.insert(&post.url, post.entry);
+    }
+
+    let published: Vec<&BlogPost> = cmd.config.blog_posts.iter()
+        .filter(|post| post.publish || cmd.global_opts.unpublished)
+        .collect();
+
+    // Posts that failed or weren't selected this time keep their previous
+    // output, and will be rebuilt next time.
+    for post in &published {
+        if manifest.get(&post.url).is_none() {
+            if let Some(entry) = old_manifest.get(&post.url) {
+                manifest.insert(&post.url, entry.clone());
             }
         }
-        Ok(())
-    });
+    }
 
-    let render_dir = cmd.global_opts.data_dir.join(RENDER_DIR);
-    let author_maps = crate::author::create_author_maps(&cmd.config)?;
-    author::write_pages(&render_dir, &cmd.config.authors, &assets, index_data.into_inner(), author_maps)?;
+    let file_names: HashSet<&String> = manifest.posts.values()
+        .map(|entry| &entry.file_name)
+        .collect();
+    for (url, entry) in &old_manifest.posts {
+        if !file_names.contains(&entry.file_name) {
+            let render_file = post_dir.join(format!("{}.html", entry.file_name));
+            if render_file.exists() {
+                fs::remove_file(&render_file)
+                    .context("removing stale post")?;
+                info!("removed {} ({})", render_file.display(), url);
+            }
+        }
+    }
+
+    manifest.save(&manifest_file)?;
+    info!("{} posts rebuilt, {} up to date", rebuilt, up_to_date);
+
+    let index_data: Vec<IndexEntry> = published.iter()
+        .filter_map(|post| {
+            manifest.get(&post.url).map(|entry| IndexEntry {
+                post_meta: (*post).clone(),
+                title: entry.title.clone(),
+                file_name: entry.file_name.clone(),
+                published: entry.published.as_deref().and_then(DateTime::parse_iso8601),
+                author: entry.author.clone(),
+            })
+        })
+        .collect();
+
+    let author_maps = author::create_author_maps(&cmd.config)?;
+    let index_assets = assets::AssetDirs {
+        css_dir: PathBuf::from("./css/"),
+    };
+    index::write(&render_dir, &index_assets, index_data.clone(), author_maps.clone())?;
+    author::write_pages(&render_dir, &cmd.config.authors, &post_assets, index_data, author_maps)?;
+
+    assets::copy(&assets::AssetDirs {
+        css_dir: render_dir.join("css"),
+    })?;
+
+    if failures > 0 {
+        bail!("{} posts failed to build", failures);
+    }
+
+    info!("built site at {}", render_dir.display());
+    Ok(())
+}
+
+/// A post that has been extracted, converted and sanitized, ready to render
+struct ProcessedPost {
+    meta: BlogPost,
+    doc: doc::Document,
+    title: String,
+    file_name: String,
+}
+
+impl ProcessedPost {
+    fn index_entry(&self) -> IndexEntry {
+        IndexEntry {
+            post_meta: self.meta.clone(),
+            title: self.title.clone(),
+            file_name: self.file_name.clone(),
+            published: self.doc.meta.page.published,
+            author: self.doc.meta.page.author.clone(),
+        }
+    }
+}
+
+/// Extracts the article and strips its boilerplate, warning when it was
+/// found by scoring and the score is low enough that it may be the wrong
+/// part of the page
+fn extract_article(post: &BlogPost, rules: &html::ExtractRules, text: &str) -> Result<(html::SubDom, html::CandidateType)> {
+    let (dom, type_) = html::extract_article(text, rules)?;
+    if let html::CandidateType::Scored(score) = type_ {
+        if score < score::LOW_SCORE {
+            warn!("low-confidence extraction of {} ({} scored {:.1})",
+                  post.url, html::describe(&dom.1), score);
+        }
+    }
+    for removed in boilerplate::strip(&dom.1) {
+        debug!("removed boilerplate from {}: {}", post.url, removed);
+    }
+    Ok((dom, type_))
+}
+
+fn process_post(meta: &BlogPost, rules: &html::ExtractRules, page: &Page) -> Result<ProcessedPost> {
+    let text = page.text();
+    let (dom, type_) = extract_article(meta, rules, &text)?;
+    let doc = convert_article(meta, page, &text, &dom)?;
+    let mut doc = sanitize::sanitize(doc, &text, type_, rules.title.as_ref());
+    let title = match &meta.title {
+        Some(title) => {
+            doc = sanitize::set_title(doc, title);
+            title.clone()
+        }
+        None => {
+            let site_title = match &rules.title {
+                Some(title) => html::select_text(&text, title)?,
+                None => None,
+            };
+            site_title.or_else(|| extract::title(&doc))
+                .ok_or_else(|| anyhow!("no title found"))?
+        }
+    };
+    // Slugs are checked when the config is loaded
+    let file_name = match &meta.slug {
+        Some(slug) => slug.clone(),
+        None => sanitize::title_to_slug(title.clone()),
+    };
+
+    Ok(ProcessedPost {
+        meta: meta.clone(),
+        doc,
+        title,
+        file_name,
+    })
+}
 
+/// Mirrors the post's images and writes it to the post directory
+fn write_post(opts: &GlobalOpts, images: &HttpCache, assets: &assets::AssetDirs, post: &mut ProcessedPost) -> Result<()> {
+    let render_dir = opts.data_dir.join(RENDER_DIR);
+    let img_dir = render_dir.join(IMG_DIR);
+    let img_href = format!("../{}/", IMG_DIR);
+    images::mirror(images, &mut post.doc, &img_dir, &img_href);
+    let doc = render::to_string(assets, &post.doc)?;
+    let post_dir = render_dir.join(POST_DIR);
+    let render_file = post_dir.join(format!("{}.html", post.file_name));
+    fs::create_dir_all(&post_dir)
+        .context("creating post dir")?;
+    fs::write(&render_file, doc)
+        .context("writing rendered doc")?;
+    info!("rendered at {}", render_file.display());
     Ok(())
 }
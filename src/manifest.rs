@@ -0,0 +1,97 @@
+//! The build manifest records the inputs each rendered post was built from,
+//! so that `build` can skip posts whose inputs haven't changed.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, Context};
+use url::Url;
+use crate::config::{BlogPost, Site};
+use crate::http_cache::content_hash;
+
+static TOOL_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// Bump this whenever a change to extraction, conversion or rendering changes
+/// the output for the same page, so that posts built before it are rebuilt
+static RENDER_FORMAT: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    /// Entries keyed by post URL
+    #[serde(default)]
+    pub posts: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub title: String,
+    pub file_name: String,
+    /// The publication date from the page's metadata, in ISO 8601
+    #[serde(default)]
+    pub published: Option<String>,
+    /// The author from the page's metadata
+    #[serde(default)]
+    pub author: Option<String>,
+    pub inputs: Inputs,
+}
+
+/// Everything that determines the rendered output of a post
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Inputs {
+    /// Hash of the cached HTML of the post
+    pub page_hash: String,
+    /// Hash of the post's entry in the config, and its site's rules
+    pub config_hash: String,
+    pub tool_version: String,
+    /// `RENDER_FORMAT` when the post was built. Manifests from before it was
+    /// recorded have 0.
+    #[serde(default)]
+    pub render_format: u32,
+}
+
+impl Inputs {
+    pub fn new(post: &BlogPost, site: Option<&Site>, page: &str) -> Result<Inputs> {
+        let mut config = toml::to_string(post)
+            .context("serializing config entry")?;
+        if let Some(site) = site {
+            config.push_str(&toml::to_string(site)
+                .context("serializing site rules")?);
+        }
+        Ok(Inputs {
+            page_hash: content_hash(page.as_bytes()),
+            config_hash: content_hash(config.as_bytes()),
+            tool_version: TOOL_VERSION.to_string(),
+            render_format: RENDER_FORMAT,
+        })
+    }
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest> {
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let manifest = fs::read_to_string(path)
+            .context("reading build manifest")?;
+        let manifest = toml::from_str(&manifest)
+            .context("parsing build manifest")?;
+        Ok(manifest)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let manifest = toml::to_string(self)
+            .context("serializing build manifest")?;
+        fs::write(path, manifest)
+            .context("writing build manifest")?;
+        Ok(())
+    }
+
+    pub fn get(&self, url: &Url) -> Option<&ManifestEntry> {
+        self.posts.get(url.as_str())
+    }
+
+    pub fn insert(&mut self, url: &Url, entry: ManifestEntry) {
+        self.posts.insert(url.as_str().to_string(), entry);
+    }
+}
@@ -0,0 +1,198 @@
+//! What a page says about its post: when it was published and updated, who
+//! wrote it, a description and the name of the site.
+//!
+//! The sources, most trusted first, are JSON-LD blocks describing the post,
+//! `<meta>` tags like Open Graph's and Twitter's, and `<time>` elements.
+//! Each only fills in what the ones before it didn't find.
+
+use std::collections::HashMap;
+use anyhow::Result;
+use log::debug;
+use serde_json::Value;
+use markup5ever_rcdom::{Handle, NodeData};
+use crate::date::DateTime;
+use crate::doc::PageMeta;
+use crate::html;
+use crate::selector::{self, Selector};
+
+/// JSON-LD types that describe a post
+static POST_TYPES: &[&str] = &[
+    "BlogPosting", "Article", "NewsArticle", "TechArticle",
+    "ScholarlyArticle", "SocialMediaPosting",
+];
+
+/// Meta tag names and properties, lowercased, for each field, in order of
+/// preference
+static PUBLISHED_TAGS: &[&str] = &[
+    "article:published_time", "datepublished", "og:published_time",
+    "dcterms.created", "dc.date", "date",
+];
+static UPDATED_TAGS: &[&str] = &[
+    "article:modified_time", "datemodified", "og:updated_time",
+    "dcterms.modified", "last-modified",
+];
+static AUTHOR_TAGS: &[&str] = &[
+    "author", "article:author", "dc.creator", "twitter:creator",
+];
+static DESCRIPTION_TAGS: &[&str] = &[
+    "description", "og:description", "twitter:description", "dc.description",
+];
+static SITE_NAME_TAGS: &[&str] = &[
+    "og:site_name", "application-name",
+];
+
+pub fn extract(src: &str) -> Result<PageMeta> {
+    let (_dom, document) = html::extract_dom(src)?;
+    let mut meta = PageMeta::default();
+    from_json_ld(&document, &mut meta);
+    from_meta_tags(&document, &mut meta);
+    from_time_elements(&document, &mut meta);
+    Ok(meta)
+}
+
+fn fill<T>(field: &mut Option<T>, value: Option<T>) {
+    if field.is_none() {
+        *field = value;
+    }
+}
+
+fn from_json_ld(document: &Handle, meta: &mut PageMeta) {
+    let scripts = Selector::parse(r#"script[type="application/ld+json"]"#)
+        .expect("bad selector");
+    for script in selector::select(document, &scripts) {
+        let value: Value = match serde_json::from_str(&raw_text(&script)) {
+            Ok(value) => value,
+            Err(e) => {
+                debug!("skipping unparseable JSON-LD: {}", e);
+                continue;
+            }
+        };
+        let mut posts = Vec::new();
+        find_posts(&value, &mut posts);
+        for post in posts {
+            let string = |key: &str| post.get(key).and_then(Value::as_str);
+            fill(&mut meta.published, string("datePublished").and_then(DateTime::parse_iso8601));
+            fill(&mut meta.updated, string("dateModified").and_then(DateTime::parse_iso8601));
+            fill(&mut meta.author, post.get("author").and_then(name));
+            fill(&mut meta.description, string("description").map(clean));
+            fill(&mut meta.site_name, post.get("publisher").and_then(name));
+        }
+    }
+}
+
+/// Collects the objects in the JSON-LD that describe posts, looking inside
+/// arrays and `@graph`s
+fn find_posts<'a>(value: &'a Value, posts: &mut Vec<&'a Value>) {
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                find_posts(value, posts);
+            }
+        }
+        Value::Object(object) => {
+            if let Some(graph) = object.get("@graph") {
+                find_posts(graph, posts);
+            }
+            let is_post = match object.get("@type") {
+                Some(Value::String(type_)) => POST_TYPES.contains(&type_.as_str()),
+                Some(Value::Array(types)) => types.iter()
+                    .filter_map(Value::as_str)
+                    .any(|type_| POST_TYPES.contains(&type_)),
+                _ => false,
+            };
+            if is_post {
+                posts.push(value);
+            }
+        }
+        _ => { }
+    }
+}
+
+/// The name of a JSON-LD person or organization, which may be just a
+/// string, or a list of them, of which the first is taken
+fn name(value: &Value) -> Option<String> {
+    match value {
+        Value::String(name) => Some(clean(name)),
+        Value::Object(object) => object.get("name").and_then(name),
+        Value::Array(values) => values.iter().find_map(name),
+        _ => None,
+    }
+    .filter(|name| !name.is_empty())
+}
+
+fn from_meta_tags(document: &Handle, meta: &mut PageMeta) {
+    let tags = Selector::parse("meta[content]").expect("bad selector");
+    let mut content: HashMap<String, String> = HashMap::new();
+    for tag in selector::select(document, &tags) {
+        let attrs = match &tag.data {
+            NodeData::Element { attrs, .. } => attrs,
+            _ => continue,
+        };
+        let key = ["property", "name", "itemprop"].iter()
+            .find_map(|attr| html::find_attr(attrs, attr));
+        let value = html::find_attr(attrs, "content").map(|value| clean(&value));
+        if let (Some(key), Some(value)) = (key, value) {
+            if !value.is_empty() {
+                content.entry(key.to_ascii_lowercase()).or_insert(value);
+            }
+        }
+    }
+
+    let first = |keys: &[&str]| keys.iter().find_map(|key| content.get(*key).cloned());
+    let first_date = |keys: &[&str]| keys.iter()
+        .filter_map(|key| content.get(*key))
+        .find_map(|value| DateTime::parse_iso8601(value));
+    // `article:author` is often a link to the author's profile
+    let author = AUTHOR_TAGS.iter()
+        .filter_map(|key| content.get(*key))
+        .find(|value| !value.starts_with("http://") && !value.starts_with("https://"))
+        .cloned();
+
+    fill(&mut meta.published, first_date(PUBLISHED_TAGS));
+    fill(&mut meta.updated, first_date(UPDATED_TAGS));
+    fill(&mut meta.author, author);
+    fill(&mut meta.description, first(DESCRIPTION_TAGS));
+    fill(&mut meta.site_name, first(SITE_NAME_TAGS));
+}
+
+/// Takes the first `<time>` that looks like an update as the updated date,
+/// and the first other one as the published date
+fn from_time_elements(document: &Handle, meta: &mut PageMeta) {
+    let times = Selector::parse("time[datetime]").expect("bad selector");
+    for time in selector::select(document, &times) {
+        let attrs = match &time.data {
+            NodeData::Element { attrs, .. } => attrs,
+            _ => continue,
+        };
+        let date = match html::find_attr(attrs, "datetime")
+            .and_then(|value| DateTime::parse_iso8601(&value)) {
+            Some(date) => date,
+            None => continue,
+        };
+        let hints = ["class", "itemprop"].iter()
+            .filter_map(|attr| html::find_attr(attrs, attr))
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_ascii_lowercase();
+        if hints.contains("updated") || hints.contains("modified") {
+            fill(&mut meta.updated, Some(date));
+        } else {
+            fill(&mut meta.published, Some(date));
+        }
+    }
+}
+
+/// The text of an element as written, without collapsing whitespace
+fn raw_text(node: &Handle) -> String {
+    let mut text = String::new();
+    html::walk_dom_fn(node, &mut |node| {
+        if let NodeData::Text { contents } = &node.data {
+            text.push_str(&contents.borrow());
+        }
+    });
+    text
+}
+
+fn clean(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,44 @@
+//! A minimal pool of scoped worker threads
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Maps `f` over `items` on up to `jobs` threads, returning the results in
+/// the order of `items`, regardless of the order they complete in.
+pub fn map<I, T, F>(jobs: usize, items: &[I], f: F) -> Vec<T>
+where I: Sync,
+      T: Send,
+      F: Fn(&I) -> T + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(items.iter().map(|_| None).collect::<Vec<Option<T>>>());
+    let workers = jobs.max(1).min(items.len());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= items.len() {
+                        break;
+                    }
+                    let result = f(&items[i]);
+                    results.lock().expect("poisoned")[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    results.into_inner().expect("poisoned")
+        .into_iter()
+        .map(|result| result.expect("worker finished without a result"))
+        .collect()
+}
+
+/// The default number of jobs: the available parallelism of the machine
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
@@ -1,9 +1,12 @@
 use std::io::Write;
+use url::Url;
 use anyhow::Result;
 use crate::doc::*;
 use v_htmlescape::escape;
 use crate::assets::AssetDirs;
 use crate::assets::{RESET_CSS_FILE, MAIN_CSS_FILE, BLOG_CSS_FILE};
+use crate::extract;
+use crate::highlight;
 
 pub fn to_string(assets: &AssetDirs, doc: &Document) -> Result<String> {
     let mut buf = Vec::new();
@@ -20,16 +23,21 @@ fn render_doc(buf: &mut Buf, assets: &AssetDirs, doc: &Document) {
 
     let header_meta = HeaderMeta {
         title: None,
+        description: doc.meta.page.description.clone(),
+        author: doc.meta.page.author.clone(),
     };
     
     render_head(buf, assets, &header_meta);
-    render_body(buf, &doc.body);
+    render_body(buf, &doc.meta, &doc.body);
 
     writeln!(buf, "</html>");
 }    
 
+#[derive(Default)]
 pub struct HeaderMeta {
     pub title: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
 }
 
 pub fn render_head(buf: &mut impl Write, assets: &AssetDirs, meta: &HeaderMeta) {
@@ -43,6 +51,12 @@ pub fn render_head(buf: &mut impl Write, assets: &AssetDirs, meta: &HeaderMeta)
     if let Some(title) = meta.title.as_ref() {
         writeln!(buf, "  <title>{}</title>", title);
     }
+    if let Some(description) = meta.description.as_ref() {
+        writeln!(buf, "  <meta name='description' content='{}'>", escape(description));
+    }
+    if let Some(author) = meta.author.as_ref() {
+        writeln!(buf, "  <meta name='author' content='{}'>", escape(author));
+    }
     writeln!(buf, "  <link rel='stylesheet' href='{}'>", reset_file.display());
     writeln!(buf, "  <link rel='stylesheet' href='{}'>", main_file.display());
     writeln!(buf, "  <link rel='stylesheet' href='{}'>", blog_file.display());
@@ -50,13 +64,24 @@ pub fn render_head(buf: &mut impl Write, assets: &AssetDirs, meta: &HeaderMeta)
     writeln!(buf);
 }
 
-fn render_body(buf: &mut Buf, body: &Body) {
+fn render_body(buf: &mut Buf, meta: &Meta, body: &Body) {
     writeln!(buf);
     writeln!(buf, "<body>");
     writeln!(buf, "<main>");
+    if let Some(snapshot) = &meta.snapshot {
+        render_snapshot_notice(buf, &meta.origin_url, snapshot);
+    }
     writeln!(buf, "<article>");
+    let mut byline_done = false;
     for block in &body.blocks {
         render_block(buf, block);
+        // The byline goes under the title
+        if !byline_done {
+            if let Block::Heading(Heading { level: HeadingLevel::H1, .. }) = block {
+                render_byline(buf, &meta.page);
+                byline_done = true;
+            }
+        }
     }
     writeln!(buf, "</article>");
     writeln!(buf, "</main>");
@@ -64,6 +89,48 @@ fn render_body(buf: &mut Buf, body: &Body) {
     writeln!(buf);
 }
 
+fn render_snapshot_notice(buf: &mut Buf, origin_url: &Url, snapshot: &Snapshot) {
+    let origin_url = escape(origin_url.as_str());
+    let snapshot_url = escape(snapshot.url.as_str());
+    write!(buf, "<p class='snapshot'>The original of this post at <a href='{}'>{}</a> is gone. \
+                 This copy is from <a href='{}'>an archived snapshot</a>",
+           origin_url, origin_url, snapshot_url);
+    if let Some(date) = &snapshot.captured_at {
+        write!(buf, " taken on {}", date.iso8601_date());
+    }
+    writeln!(buf, ".</p>");
+}
+
+fn render_byline(buf: &mut Buf, page: &PageMeta) {
+    if page.author.is_none() && page.published.is_none() && page.updated.is_none() {
+        return;
+    }
+    writeln!(buf);
+    write!(buf, "<p class='byline'>");
+    if let Some(author) = &page.author {
+        write!(buf, "By {}", escape(author));
+        if page.published.is_some() {
+            write!(buf, ", ");
+        }
+    }
+    if let Some(date) = &page.published {
+        write!(buf, "<time datetime='{}'>{}</time>", date.iso8601(), date.iso8601_date());
+    }
+    if let Some(updated) = &page.updated {
+        match &page.published {
+            Some(published) if published.iso8601_date() == updated.iso8601_date() => { }
+            Some(_) => {
+                write!(buf, " (updated <time datetime='{}'>{}</time>)", updated.iso8601(), updated.iso8601_date());
+            }
+            None => {
+                let updated_label = if page.author.is_some() { ", updated" } else { "Updated" };
+                write!(buf, "{} <time datetime='{}'>{}</time>", updated_label, updated.iso8601(), updated.iso8601_date());
+            }
+        }
+    }
+    writeln!(buf, "</p>");
+}
+
 fn render_block(buf: &mut Buf, block: &Block) {
     writeln!(buf);
     match block {
@@ -85,6 +152,17 @@ fn render_block(buf: &mut Buf, block: &Block) {
         Block::CodeBlock(code_block) => {
             render_code_block(buf, code_block);
         }
+        Block::Image(image) => {
+            write!(buf, "<p>");
+            render_image(buf, image);
+            writeln!(buf, "</p>");
+        }
+        Block::Figure(figure) => {
+            render_figure(buf, figure);
+        }
+        Block::Table(table) => {
+            render_table(buf, table);
+        }
     }
     writeln!(buf);
 }
@@ -140,9 +218,90 @@ fn render_inline(buf: &mut Buf, inline: &Inline) {
             }
             write!(buf, "</code>");
         }
+        Inline::Link(link) => {
+            write!(buf, "<a href='{}'", escape(&link.href));
+            if let Some(title) = &link.title {
+                write!(buf, " title='{}'", escape(title));
+            }
+            write!(buf, ">");
+            for inline in &link.inlines {
+                render_inline(buf, inline);
+            }
+            write!(buf, "</a>");
+        }
+        Inline::Image(image) => {
+            render_image(buf, image);
+        }
     }
 }
 
+fn render_image(buf: &mut Buf, image: &Image) {
+    let alt = image.alt.as_ref().map(String::as_str).unwrap_or("");
+    write!(buf, "<img src='{}' alt='{}'", escape(&image.src), escape(alt));
+    if let Some(title) = &image.title {
+        write!(buf, " title='{}'", escape(title));
+    }
+    write!(buf, ">");
+}
+
+fn render_figure(buf: &mut Buf, figure: &Figure) {
+    writeln!(buf, "<figure>");
+    render_image(buf, &figure.image);
+    writeln!(buf);
+    if !figure.caption.is_empty() {
+        write!(buf, "<figcaption>");
+        for inline in &figure.caption {
+            render_inline(buf, inline);
+        }
+        writeln!(buf, "</figcaption>");
+    }
+    writeln!(buf, "</figure>");
+}
+
+fn render_table(buf: &mut Buf, table: &Table) {
+    writeln!(buf, "<div class='table'>");
+    writeln!(buf, "<table>");
+    if !table.caption.is_empty() {
+        write!(buf, "<caption>");
+        for inline in &table.caption {
+            render_inline(buf, inline);
+        }
+        writeln!(buf, "</caption>");
+    }
+    if !table.header.is_empty() {
+        writeln!(buf, "<thead>");
+        for row in &table.header {
+            render_table_row(buf, row, &table.alignments, "th");
+        }
+        writeln!(buf, "</thead>");
+    }
+    writeln!(buf, "<tbody>");
+    for row in &table.body {
+        render_table_row(buf, row, &table.alignments, "td");
+    }
+    writeln!(buf, "</tbody>");
+    writeln!(buf, "</table>");
+    writeln!(buf, "</div>");
+}
+
+fn render_table_row(buf: &mut Buf, row: &TableRow, alignments: &[Alignment], tag: &str) {
+    write!(buf, "<tr>");
+    for (column, cell) in row.cells.iter().enumerate() {
+        let alignment = alignments.get(column).cloned().unwrap_or(Alignment::Default);
+        match alignment {
+            Alignment::Default => write!(buf, "<{}>", tag),
+            Alignment::Left => write!(buf, "<{} class='align-left'>", tag),
+            Alignment::Center => write!(buf, "<{} class='align-center'>", tag),
+            Alignment::Right => write!(buf, "<{} class='align-right'>", tag),
+        };
+        for inline in &cell.inlines {
+            render_inline(buf, inline);
+        }
+        write!(buf, "</{}>", tag);
+    }
+    writeln!(buf, "</tr>");
+}
+
 fn render_list(buf: &mut Buf, list: &List) {
     let tag = match list.type_ {
         ListType::Unordered => "ul",
@@ -178,9 +337,34 @@ fn render_thematic_break(buf: &mut Buf) {
 }
 
 fn render_code_block(buf: &mut Buf, code_block: &CodeBlock) {
-    write!(buf, "<pre><code>");
-    for inline in &code_block.inlines {
-        render_inline(buf, inline);
+    match code_block.lang.name() {
+        Some(name) => write!(buf, "<pre><code class='language-{}'>", name),
+        None => write!(buf, "<pre><code>"),
+    };
+    match code_block.lang {
+        CodeLang::Rust => {
+            // Any markup from the source blog's highlighter is discarded
+            let code = extract::inlines_text(&code_block.inlines);
+            render_highlighted(buf, highlight::rust(&code));
+        }
+        _ => {
+            for inline in &code_block.inlines {
+                render_inline(buf, inline);
+            }
+        }
     }
     writeln!(buf, "</code></pre>");
 }
+
+fn render_highlighted(buf: &mut Buf, spans: Vec<highlight::Span<'_>>) {
+    for (class, text) in spans {
+        match class {
+            Some(class) => {
+                write!(buf, "<span class='{}'>{}</span>", class.css_class(), escape(text));
+            }
+            None => {
+                write!(buf, "{}", escape(text));
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use log::info;
+use std::collections::BTreeMap;
+use url::Url;
+use markup5ever_rcdom as rcdom;
+use rcdom::{Handle, NodeData};
+use crate::doc::Document;
+use crate::html::{SubDom, CandidateType};
+use crate::extract;
+use crate::convert::{Diagnostic, DiagnosticKind};
+
+/// How much of an article survived conversion
+pub struct PostReport {
+    pub url: Url,
+    /// How the article was found, with the score if by scoring
+    pub extraction: CandidateType,
+    /// Non-whitespace characters of text in the extracted article
+    pub dom_chars: usize,
+    /// Non-whitespace characters of text in the converted document
+    pub doc_chars: usize,
+    /// Elements the converter skipped or dropped, by tag name
+    pub skipped: BTreeMap<String, usize>,
+}
+
+pub fn post_report(url: &Url, extraction: CandidateType, dom: &SubDom, doc: &Document, diagnostics: &[Diagnostic]) -> PostReport {
+    let mut dom_text = String::new();
+    cat_dom_text(&mut dom_text, &dom.1);
+    let doc_text = extract::text(doc);
+
+    let mut skipped = BTreeMap::new();
+    for diagnostic in diagnostics {
+        match &diagnostic.kind {
+            DiagnosticKind::SkippedElement(name) |
+            DiagnosticKind::DroppedElement(name) => {
+                *skipped.entry(name.clone()).or_insert(0) += 1;
+            }
+            _ => { }
+        }
+    }
+
+    PostReport {
+        url: url.clone(),
+        extraction,
+        dom_chars: count_chars(&dom_text),
+        doc_chars: count_chars(&doc_text),
+        skipped,
+    }
+}
+
+/// Logs a table of the survival rate and skipped elements of each post,
+/// followed by totals over all posts.
+pub fn print(reports: &[PostReport]) {
+    info!("{:>8} {:>8} {:>6}  {:<12}  {}", "dom", "doc", "kept", "extraction", "post");
+    for report in reports {
+        info!("{:>8} {:>8} {:>6}  {:<12}  {}",
+              report.dom_chars, report.doc_chars,
+              percent(report.doc_chars, report.dom_chars),
+              report.extraction.to_string(), report.url);
+        if !report.skipped.is_empty() {
+            let skipped: Vec<String> = by_count(&report.skipped).iter()
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect();
+            info!("{:>39}  skipped: {}", "", skipped.join(", "));
+        }
+    }
+
+    let dom_chars = reports.iter().map(|r| r.dom_chars).sum();
+    let doc_chars = reports.iter().map(|r| r.doc_chars).sum();
+    info!("{:>8} {:>8} {:>6}  {:<12}  total over {} posts",
+          dom_chars, doc_chars, percent(doc_chars, dom_chars), "", reports.len());
+
+    let scored: Vec<&PostReport> = reports.iter()
+        .filter(|r| matches!(r.extraction, CandidateType::Scored(_)))
+        .collect();
+    if !scored.is_empty() {
+        info!("");
+        info!("{} posts were extracted by scoring", scored.len());
+    }
+
+    let mut skipped = BTreeMap::new();
+    let mut posts = BTreeMap::new();
+    for report in reports {
+        for (name, count) in &report.skipped {
+            *skipped.entry(name.clone()).or_insert(0) += count;
+            *posts.entry(name.clone()).or_insert(0) += 1;
+        }
+    }
+
+    info!("");
+    info!("{:>8} {:>8}  {}", "count", "posts", "skipped element");
+    for (name, count) in by_count(&skipped) {
+        info!("{:>8} {:>8}  {}", count, posts[name], name);
+    }
+}
+
+fn by_count(counts: &BTreeMap<String, usize>) -> Vec<(&String, usize)> {
+    let mut counts: Vec<(&String, usize)> = counts.iter()
+        .map(|(name, count)| (name, *count))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    counts
+}
+
+fn percent(part: usize, whole: usize) -> String {
+    if whole == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.1}%", part as f64 * 100.0 / whole as f64)
+    }
+}
+
+fn count_chars(s: &str) -> usize {
+    s.chars().filter(|c| !c.is_whitespace()).count()
+}
+
+/// Collects the text of the DOM, except that of elements that are never
+/// meant to be displayed.
+fn cat_dom_text(buf: &mut String, node: &Handle) {
+    match &node.data {
+        NodeData::Element { name, .. } => {
+            match name.local.as_ref() {
+                "script" | "style" | "noscript" | "template" => {
+                    return;
+                }
+                _ => { }
+            }
+        }
+        NodeData::Text { contents } => {
+            buf.push_str(&contents.borrow());
+        }
+        _ => { }
+    }
+
+    for child in node.children.borrow().iter() {
+        cat_dom_text(buf, child);
+    }
+}
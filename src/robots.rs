@@ -0,0 +1,216 @@
+//! Parsing and matching of robots.txt files, as described in RFC 9309
+
+/// The rules of a robots.txt file that apply to one user agent
+#[derive(Debug, Clone, Default)]
+pub struct Robots {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl Robots {
+    /// Rules that allow everything, for hosts without a robots.txt
+    pub fn allow_all() -> Robots {
+        Robots::default()
+    }
+
+    /// Parses the rules of the group that matches `user_agent`, or of the
+    /// `*` group if none does
+    pub fn parse(src: &str, user_agent: &str) -> Robots {
+        // Matching is on the product token, e.g. `rust-doc-tool` in
+        // `rust-doc-tool/0.1.0`
+        let product = user_agent.split(|c: char| c == '/' || c.is_whitespace())
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+
+        let mut specific = Vec::new();
+        let mut any = Vec::new();
+        let mut found_specific = false;
+
+        // Consecutive user-agent lines start a group that the rules after
+        // them belong to
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        for line in src.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut parts = line.splitn(2, ':');
+            let key = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => continue,
+            };
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        agents.clear();
+                        in_rules = false;
+                    }
+                    let agent = value.to_ascii_lowercase();
+                    // A group for us applies even if it has no rules, or
+                    // only an empty disallow
+                    if !product.is_empty() && agent == product {
+                        found_specific = true;
+                    }
+                    agents.push(agent);
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // An empty disallow allows everything
+                    if value.is_empty() {
+                        continue;
+                    }
+                    let rule = Rule {
+                        allow: key == "allow",
+                        pattern: value.to_string(),
+                    };
+                    if !product.is_empty() && agents.contains(&product) {
+                        specific.push(rule.clone());
+                    }
+                    if agents.iter().any(|a| a == "*") {
+                        any.push(rule);
+                    }
+                }
+                _ => { }
+            }
+        }
+
+        Robots {
+            rules: if found_specific { specific } else { any },
+        }
+    }
+
+    /// Whether the path, with its query, may be fetched. The most specific
+    /// matching rule wins, and allow wins ties.
+    pub fn allowed(&self, path: &str) -> bool {
+        let mut best: Option<&Rule> = None;
+        for rule in &self.rules {
+            if !matches(&rule.pattern, path) {
+                continue;
+            }
+            best = match best {
+                Some(best) if best.pattern.len() > rule.pattern.len() => Some(best),
+                Some(best) if best.pattern.len() == rule.pattern.len() && best.allow => Some(best),
+                _ => Some(rule),
+            };
+        }
+        best.map(|rule| rule.allow).unwrap_or(true)
+    }
+}
+
+/// Matches a path against a pattern, where `*` matches any run of characters
+/// and a trailing `$` anchors the end
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !path.starts_with(first) {
+        return false;
+    }
+    let mut rest = &path[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        let last = i == parts.len() - 1;
+        if last && anchored {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_prefixes() {
+        assert!(matches("/private", "/private"));
+        assert!(matches("/private", "/private/page.html"));
+        assert!(matches("/", "/anything"));
+        assert!(!matches("/private", "/public"));
+        assert!(!matches("/private/", "/private"));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("/*.php", "/index.php"));
+        assert!(matches("/*.php", "/dir/index.php?x=1"));
+        assert!(matches("/a*b*c", "/a-b-c"));
+        assert!(!matches("/a*b*c", "/a-c-b"));
+        assert!(matches("*", "/anything"));
+    }
+
+    #[test]
+    fn matches_anchored() {
+        assert!(matches("/*.php$", "/index.php"));
+        assert!(!matches("/*.php$", "/index.php?x=1"));
+        assert!(matches("/exact$", "/exact"));
+        assert!(!matches("/exact$", "/exact/more"));
+        assert!(matches("/a*$", "/a/b"));
+    }
+
+    #[test]
+    fn longest_match_wins() {
+        let robots = Robots::parse("User-agent: *\n\
+                                    Disallow: /blog/\n\
+                                    Allow: /blog/public/\n", "rust-doc-tool/0.1.0");
+        assert!(!robots.allowed("/blog/private.html"));
+        assert!(robots.allowed("/blog/public/post.html"));
+        assert!(robots.allowed("/other"));
+    }
+
+    #[test]
+    fn allow_wins_ties() {
+        let robots = Robots::parse("User-agent: *\n\
+                                    Disallow: /page\n\
+                                    Allow: /page\n", "rust-doc-tool");
+        assert!(robots.allowed("/page"));
+    }
+
+    #[test]
+    fn specific_group_wins() {
+        let src = "User-agent: *\n\
+                   Disallow: /\n\
+                   \n\
+                   User-agent: Rust-Doc-Tool\n\
+                   Disallow: /private\n";
+        let robots = Robots::parse(src, "rust-doc-tool/0.1.0");
+        assert!(robots.allowed("/post"));
+        assert!(!robots.allowed("/private/post"));
+
+        let robots = Robots::parse(src, "other-bot/1.0");
+        assert!(!robots.allowed("/post"));
+    }
+
+    #[test]
+    fn empty_disallow_in_specific_group_allows_all() {
+        let src = "User-agent: *\n\
+                   Disallow: /\n\
+                   \n\
+                   User-agent: rust-doc-tool\n\
+                   Disallow:\n";
+        let robots = Robots::parse(src, "rust-doc-tool/0.1.0");
+        assert!(robots.allowed("/post"));
+    }
+
+    #[test]
+    fn shared_group() {
+        let src = "User-agent: other-bot\n\
+                   User-agent: rust-doc-tool\n\
+                   Disallow: /shared\n";
+        let robots = Robots::parse(src, "rust-doc-tool/0.1.0");
+        assert!(!robots.allowed("/shared/post"));
+        assert!(robots.allowed("/post"));
+    }
+}
@@ -5,9 +5,30 @@ use markup5ever_rcdom as rcdom;
 use rcdom::{Node, NodeData};
 use crate::html::{SubDom, CandidateType};
 use crate::doc::{Block, HeadingLevel};
+use url::Url;
+use crate::selector::{self, Selector};
 
-pub fn sanitize(doc: Document, post: &str, candidate_type: CandidateType) -> Document {
-    let doc = maybe_add_h1(doc, post, candidate_type);
+/// Fixes up the converted article. `title` is the site's title selector,
+/// if it has one.
+pub fn sanitize(doc: Document, post: &str, candidate_type: CandidateType, title: Option<&Selector>) -> Document {
+    let doc = maybe_add_h1(doc, post, candidate_type, title);
+    doc
+}
+
+/// Replaces the text of the document's first h1, or adds one if there isn't
+/// one, for posts whose title is overridden
+pub fn set_title(mut doc: Document, title: &str) -> Document {
+    let h1 = Heading {
+        inlines: vec![Inline::Text(title.to_string())],
+        level: HeadingLevel::H1,
+    };
+    let existing = doc.body.blocks.iter_mut().find(|block| {
+        matches!(block, Block::Heading(Heading { level: HeadingLevel::H1, .. }))
+    });
+    match existing {
+        Some(block) => *block = Block::Heading(h1),
+        None => doc.body.blocks.insert(0, Block::Heading(h1)),
+    }
     doc
 }
 
@@ -75,10 +96,15 @@ fn remove_leading_and_trailing_dashes(mut s: String) -> String {
 /// Some blogs don't put their h1 title inside the `article` tag (e.g.
 /// burntsushi). This hack looks for cases where there's the extracted doc
 /// contains no h1 before other headers, then looks for an h1 inside the dom and
-/// stuff it into the doc.
-fn maybe_add_h1(mut doc: Document, post: &str, candidate_type: CandidateType) -> Document {
+/// stuff it into the doc. Sites with a title selector get their h1 from that
+/// instead.
+fn maybe_add_h1(mut doc: Document, post: &str, candidate_type: CandidateType, title: Option<&Selector>) -> Document {
     if missing_h1(&doc) && candidate_type != CandidateType::Dreamwidth {
-        if let Some(h1) = find_h1(post) {
+        let h1 = match title {
+            Some(title) => title_h1(post, title),
+            None => find_h1(post, &doc.meta.origin_url),
+        };
+        if let Some(h1) = h1 {
             info!("subbing h1 from outer html in {:?}", doc.meta.origin_url);
             doc.body.blocks.insert(0, Block::Heading(h1));
         } else {
@@ -108,24 +134,23 @@ fn missing_h1(doc: &Document) -> bool {
 use crate::html;
 use crate::convert;
 
-fn find_h1(post: &str) -> Option<Heading> {
-    let dom = html::extract_dom(post);
-    match dom {
-        Ok(dom) => {
-            let body = convert::body_from_dom(&dom);
-            for block in body.blocks {
-                match block {
-                    Block::Heading(h) => {
-                        if h.level == HeadingLevel::H1 {
-                            return Some(h);
-                        }
-                    }
-                    _ => { }
-                }
-            }
+fn title_h1(post: &str, title: &Selector) -> Option<Heading> {
+    let text = html::select_text(post, title).ok()??;
+    Some(Heading {
+        inlines: vec![Inline::Text(text)],
+        level: HeadingLevel::H1,
+    })
+}
 
-            None
+fn find_h1(post: &str, base_url: &Url) -> Option<Heading> {
+    let (dom, document) = html::extract_dom(post).ok()?;
+    let h1 = Selector::parse("h1").expect("bad selector");
+    let h1 = selector::select_first(&document, &h1)?;
+    let (body, _) = convert::body_from_dom(&(dom, h1), base_url).ok()?;
+    body.blocks.into_iter().find_map(|block| {
+        match block {
+            Block::Heading(h) if h.level == HeadingLevel::H1 => Some(h),
+            _ => None,
         }
-        Err(_) => None
-    }
+    })
 }
@@ -0,0 +1,119 @@
+//! A Readability-style fallback for finding the article in pages with no
+//! element that marks it.
+//!
+//! Each paragraph with a fair amount of text scores points for its parent,
+//! and half as many for its grandparent. Elements start with points for
+//! their tag and for class and id hints, like `entry` or `sidebar`, and the
+//! total is scaled down by the share of their text that is in links. The
+//! highest scoring element is taken as the article.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use regex::Regex;
+use markup5ever_rcdom as rcdom;
+use rcdom::{Handle, NodeData};
+use crate::html::{self, parent};
+use crate::selector::{self, Selector};
+
+/// Scores below this are likely to have picked the wrong element
+pub static LOW_SCORE: f64 = 20.0;
+
+/// Paragraphs shorter than this, in chars, don't count
+static MIN_PARAGRAPH_LEN: usize = 25;
+
+static POSITIVE_HINTS: &'static str =
+    "article|body|content|entry|hentry|main|page|post|text|blog|story";
+static NEGATIVE_HINTS: &'static str =
+    "comment|contact|foot|masthead|media|meta|promo|related|scroll|shoutbox|\
+     sidebar|sponsor|shopping|tags|tool|widget|nav|menu|share|social|header|banner";
+
+/// The element most likely to be the article, and its score
+pub fn best_candidate(document: &Handle) -> Option<(Handle, f64)> {
+    let positive = Regex::new(&format!("(?i){}", POSITIVE_HINTS)).expect("bad regex");
+    let negative = Regex::new(&format!("(?i){}", NEGATIVE_HINTS)).expect("bad regex");
+    let paragraphs = Selector::parse("p, pre, td").expect("bad selector");
+
+    // Keyed by node address
+    let mut candidates: HashMap<*const rcdom::Node, (Handle, f64)> = HashMap::new();
+    for paragraph in selector::select(document, &paragraphs) {
+        let text = html::text(&paragraph);
+        let len = text.chars().count();
+        if len < MIN_PARAGRAPH_LEN {
+            continue;
+        }
+        let points = 1.0
+            + text.matches(',').count() as f64
+            + (len as f64 / 100.0).min(3.0);
+
+        let parent_node = match parent(&paragraph) {
+            Some(parent_node) => parent_node,
+            None => continue,
+        };
+        let grandparent = parent(&parent_node);
+        let ancestors = Some((parent_node, points)).into_iter()
+            .chain(grandparent.map(|g| (g, points / 2.0)));
+        for (ancestor, points) in ancestors {
+            if !is_element(&ancestor) {
+                continue;
+            }
+            let entry = candidates.entry(Rc::as_ptr(&ancestor))
+                .or_insert_with(|| {
+                    let score = initial_score(&ancestor, &positive, &negative);
+                    (ancestor.clone(), score)
+                });
+            entry.1 += points;
+        }
+    }
+
+    candidates.into_iter()
+        .map(|(_, (node, score))| {
+            let score = score * (1.0 - link_density(&node));
+            (node, score)
+        })
+        .max_by(|a, b| a.1.partial_cmp(&b.1).expect("nan score"))
+}
+
+fn initial_score(node: &Handle, positive: &Regex, negative: &Regex) -> f64 {
+    let (name, attrs) = match &node.data {
+        NodeData::Element { name, attrs, .. } => (name, attrs),
+        _ => return 0.0,
+    };
+    let mut score = match name.local.as_ref() {
+        "div" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" | "form" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    };
+    for attr in &["class", "id"] {
+        if let Some(value) = html::find_attr(attrs, attr) {
+            if negative.is_match(&value) {
+                score -= 25.0;
+            }
+            if positive.is_match(&value) {
+                score += 25.0;
+            }
+        }
+    }
+    score
+}
+
+/// The share of the element's text that is inside links
+fn link_density(node: &Handle) -> f64 {
+    let len = html::text(node).chars().count();
+    if len == 0 {
+        return 0.0;
+    }
+    let links = Selector::parse("a").expect("bad selector");
+    let link_len: usize = selector::select(node, &links).iter()
+        .map(|link| html::text(link).chars().count())
+        .sum();
+    (link_len as f64 / len as f64).min(1.0)
+}
+
+fn is_element(node: &Handle) -> bool {
+    match &node.data {
+        NodeData::Element { name, .. } => name.local.as_ref() != "html",
+        _ => false,
+    }
+}
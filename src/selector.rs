@@ -0,0 +1,570 @@
+//! CSS selectors over the rcdom tree.
+//!
+//! Supported are the common parts of CSS 2 and 3:
+//!
+//! - type, `*`, `#id` and `.class` selectors
+//! - attribute selectors: `[a]`, `[a=v]`, `[a~=v]`, `[a|=v]`, `[a^=v]`,
+//!   `[a$=v]` and `[a*=v]`, with quoted or bare values
+//! - `:not(...)`, taking a list of compound selectors, and `:first-child`,
+//!   `:last-child` and `:only-child`
+//! - the descendant, child (`>`), next-sibling (`+`) and subsequent-sibling
+//!   (`~`) combinators
+//! - comma-separated lists
+//!
+//! Names are matched case-insensitively, and attribute values and classes
+//! case-sensitively, as in HTML documents.
+
+use std::fmt;
+use std::rc::Rc;
+use anyhow::{Result, bail};
+use markup5ever_rcdom as rcdom;
+use rcdom::{Handle, NodeData};
+use crate::html::{self, parent};
+
+#[derive(Debug, Clone)]
+pub struct Selector {
+    source: String,
+    /// Matches if any alternative does
+    alternatives: Vec<Complex>,
+}
+
+/// Compounds joined by combinators. `combinators[i]` is between
+/// `compounds[i]` and `compounds[i + 1]`.
+#[derive(Debug, Clone)]
+struct Complex {
+    compounds: Vec<Compound>,
+    combinators: Vec<Combinator>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Combinator {
+    Descendant,
+    Child,
+    NextSibling,
+    SubsequentSibling,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Compound {
+    tag: Option<String>,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Id(String),
+    Class(String),
+    Attr {
+        name: String,
+        test: Option<(AttrOp, String)>,
+    },
+    Not(Vec<Compound>),
+    FirstChild,
+    LastChild,
+    OnlyChild,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AttrOp {
+    /// `=`
+    Equals,
+    /// `~=`, one of the whitespace-separated words
+    Includes,
+    /// `|=`, equal or followed by `-`
+    DashMatch,
+    /// `^=`
+    Prefix,
+    /// `$=`
+    Suffix,
+    /// `*=`
+    Substring,
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Selector {
+    pub fn parse(source: &str) -> Result<Selector> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let alternatives = parser.selector_list()
+            .map_err(|e| e.context(format!("invalid selector '{}'", source)))?;
+        Ok(Selector {
+            source: source.to_string(),
+            alternatives,
+        })
+    }
+
+    pub fn matches(&self, node: &Handle) -> bool {
+        self.alternatives.iter().any(|complex| complex.matches(node))
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => bail!("expected '{}', found '{}'", expected, c),
+            None => bail!("expected '{}', found the end", expected),
+        }
+    }
+
+    /// Skips whitespace, returning whether there was any
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        while self.peek().map(char::is_whitespace).unwrap_or(false) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn selector_list(&mut self) -> Result<Vec<Complex>> {
+        let mut alternatives = Vec::new();
+        loop {
+            self.skip_whitespace();
+            alternatives.push(self.complex()?);
+            match self.next() {
+                Some(',') => continue,
+                None => return Ok(alternatives),
+                Some(c) => bail!("unexpected '{}'", c),
+            }
+        }
+    }
+
+    fn complex(&mut self) -> Result<Complex> {
+        let mut compounds = vec![self.compound()?];
+        let mut combinators = Vec::new();
+        loop {
+            let space = self.skip_whitespace();
+            let combinator = match self.peek() {
+                Some('>') => Combinator::Child,
+                Some('+') => Combinator::NextSibling,
+                Some('~') => Combinator::SubsequentSibling,
+                Some(',') | None => break,
+                Some(_) if space => Combinator::Descendant,
+                Some(c) => bail!("unexpected '{}'", c),
+            };
+            if combinator != Combinator::Descendant {
+                self.pos += 1;
+                self.skip_whitespace();
+            }
+            combinators.push(combinator);
+            compounds.push(self.compound()?);
+        }
+        Ok(Complex { compounds, combinators })
+    }
+
+    fn compound(&mut self) -> Result<Compound> {
+        let mut compound = Compound::default();
+        let start = self.pos;
+        if self.peek() == Some('*') {
+            self.pos += 1;
+        } else if self.peek().map(is_ident_char).unwrap_or(false) {
+            compound.tag = Some(self.ident()?.to_ascii_lowercase());
+        }
+
+        loop {
+            let condition = match self.peek() {
+                Some('#') => {
+                    self.pos += 1;
+                    Condition::Id(self.ident()?)
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    Condition::Class(self.ident()?)
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    self.attr()?
+                }
+                Some(':') => {
+                    self.pos += 1;
+                    self.pseudo_class()?
+                }
+                _ => break,
+            };
+            compound.conditions.push(condition);
+        }
+
+        if self.pos == start {
+            match self.peek() {
+                Some(c) => bail!("expected a selector, found '{}'", c),
+                None => bail!("expected a selector, found the end"),
+            }
+        }
+        Ok(compound)
+    }
+
+    fn attr(&mut self) -> Result<Condition> {
+        self.skip_whitespace();
+        let name = self.ident()?.to_ascii_lowercase();
+        self.skip_whitespace();
+        let op = match self.next() {
+            Some(']') => return Ok(Condition::Attr { name, test: None }),
+            Some('=') => AttrOp::Equals,
+            Some(c) => {
+                let op = match c {
+                    '~' => AttrOp::Includes,
+                    '|' => AttrOp::DashMatch,
+                    '^' => AttrOp::Prefix,
+                    '$' => AttrOp::Suffix,
+                    '*' => AttrOp::Substring,
+                    _ => bail!("unexpected '{}' in attribute selector", c),
+                };
+                self.expect('=')?;
+                op
+            }
+            None => bail!("unterminated attribute selector"),
+        };
+        self.skip_whitespace();
+        let value = match self.peek() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                self.pos += 1;
+                let mut value = String::new();
+                loop {
+                    match self.next() {
+                        Some(c) if c == quote => break,
+                        Some('\\') => value.extend(self.next()),
+                        Some(c) => value.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                value
+            }
+            _ => self.ident()?,
+        };
+        self.skip_whitespace();
+        self.expect(']')?;
+        Ok(Condition::Attr {
+            name,
+            test: Some((op, value)),
+        })
+    }
+
+    fn pseudo_class(&mut self) -> Result<Condition> {
+        let name = self.ident()?.to_ascii_lowercase();
+        match name.as_str() {
+            "first-child" => Ok(Condition::FirstChild),
+            "last-child" => Ok(Condition::LastChild),
+            "only-child" => Ok(Condition::OnlyChild),
+            "not" => {
+                self.expect('(')?;
+                let mut compounds = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    compounds.push(self.compound()?);
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => continue,
+                        Some(')') => break,
+                        Some(c) => bail!("unexpected '{}' in :not()", c),
+                        None => bail!("unterminated :not()"),
+                    }
+                }
+                Ok(Condition::Not(compounds))
+            }
+            _ => bail!("unsupported pseudo-class ':{}'", name),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        let mut ident = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\\' {
+                self.pos += 1;
+                ident.extend(self.next());
+            } else if is_ident_char(c) {
+                self.pos += 1;
+                ident.push(c);
+            } else {
+                break;
+            }
+        }
+        if ident.is_empty() {
+            match self.peek() {
+                Some(c) => bail!("expected a name, found '{}'", c),
+                None => bail!("expected a name, found the end"),
+            }
+        }
+        Ok(ident)
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii()
+}
+
+impl Complex {
+    fn matches(&self, node: &Handle) -> bool {
+        self.matches_at(self.compounds.len() - 1, node)
+    }
+
+    /// Whether `node` matches the selector up to and including compound `i`
+    fn matches_at(&self, i: usize, node: &Handle) -> bool {
+        if !self.compounds[i].matches(node) {
+            return false;
+        }
+        if i == 0 {
+            return true;
+        }
+        match self.combinators[i - 1] {
+            Combinator::Child => {
+                parent(node).map(|p| self.matches_at(i - 1, &p)).unwrap_or(false)
+            }
+            Combinator::Descendant => {
+                let mut current = parent(node);
+                while let Some(ancestor) = current {
+                    if self.matches_at(i - 1, &ancestor) {
+                        return true;
+                    }
+                    current = parent(&ancestor);
+                }
+                false
+            }
+            Combinator::NextSibling => {
+                previous_siblings(node).last()
+                    .map(|s| self.matches_at(i - 1, s))
+                    .unwrap_or(false)
+            }
+            Combinator::SubsequentSibling => {
+                previous_siblings(node).iter().any(|s| self.matches_at(i - 1, s))
+            }
+        }
+    }
+}
+
+impl Compound {
+    fn matches(&self, node: &Handle) -> bool {
+        let name = match &node.data {
+            NodeData::Element { name, .. } => name,
+            _ => return false,
+        };
+        if let Some(tag) = &self.tag {
+            if !name.local.as_ref().eq_ignore_ascii_case(tag) {
+                return false;
+            }
+        }
+        self.conditions.iter().all(|condition| condition.matches(node))
+    }
+}
+
+impl Condition {
+    fn matches(&self, node: &Handle) -> bool {
+        let attrs = match &node.data {
+            NodeData::Element { attrs, .. } => attrs,
+            _ => return false,
+        };
+        match self {
+            Condition::Id(id) => {
+                html::find_attr(attrs, "id").as_deref() == Some(id.as_str())
+            }
+            Condition::Class(class) => {
+                html::find_attr(attrs, "class")
+                    .map(|c| c.split_whitespace().any(|c| c == class))
+                    .unwrap_or(false)
+            }
+            Condition::Attr { name, test } => {
+                let value = match html::find_attr(attrs, name) {
+                    Some(value) => value,
+                    None => return false,
+                };
+                let (op, expected) = match test {
+                    Some(test) => test,
+                    None => return true,
+                };
+                let expected = expected.as_str();
+                match op {
+                    AttrOp::Equals => value == expected,
+                    AttrOp::Includes => value.split_whitespace().any(|w| w == expected),
+                    AttrOp::DashMatch => {
+                        value == expected || value.starts_with(&format!("{}-", expected))
+                    }
+                    AttrOp::Prefix => !expected.is_empty() && value.starts_with(expected),
+                    AttrOp::Suffix => !expected.is_empty() && value.ends_with(expected),
+                    AttrOp::Substring => !expected.is_empty() && value.contains(expected),
+                }
+            }
+            Condition::Not(compounds) => {
+                !compounds.iter().any(|compound| compound.matches(node))
+            }
+            Condition::FirstChild => previous_siblings(node).is_empty(),
+            Condition::LastChild => next_siblings(node).is_empty(),
+            Condition::OnlyChild => {
+                previous_siblings(node).is_empty() && next_siblings(node).is_empty()
+            }
+        }
+    }
+}
+
+/// The element siblings before the node, in document order
+fn previous_siblings(node: &Handle) -> Vec<Handle> {
+    let (before, _) = element_siblings(node);
+    before
+}
+
+/// The element siblings after the node, in document order
+fn next_siblings(node: &Handle) -> Vec<Handle> {
+    let (_, after) = element_siblings(node);
+    after
+}
+
+fn element_siblings(node: &Handle) -> (Vec<Handle>, Vec<Handle>) {
+    let parent = match parent(node) {
+        Some(parent) => parent,
+        None => return (Vec::new(), Vec::new()),
+    };
+    let children = parent.children.borrow();
+    let elements = children.iter()
+        .filter(|child| matches!(child.data, NodeData::Element { .. }));
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    let mut found = false;
+    for child in elements {
+        if Rc::ptr_eq(child, node) {
+            found = true;
+        } else if found {
+            after.push(child.clone());
+        } else {
+            before.push(child.clone());
+        }
+    }
+    (before, after)
+}
+
+/// All the elements under `root`, including it, that match, in document
+/// order
+pub fn select(root: &Handle, selector: &Selector) -> Vec<Handle> {
+    let mut matches = Vec::new();
+    html::walk_dom_fn(root, &mut |node| {
+        if selector.matches(node) {
+            matches.push(node.clone());
+        }
+    });
+    matches
+}
+
+pub fn select_first(root: &Handle, selector: &Selector) -> Option<Handle> {
+    select(root, selector).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DOC: &str = r#"<html><body>
+        <div id="main" class="post content">
+          <h1 id="title">Title</h1>
+          <p id="p1" lang="en-US" data-x="">One</p>
+          <p id="p2" lang="en" title="foo bar">Two</p>
+          <section id="s1"><p id="p3" title="foobar">Three</p></section>
+          <p id="p4" title="barfoo">Four</p>
+        </div>
+        <ul id="list"><li id="only">Only</li></ul>
+        </body></html>"#;
+
+    /// The ids of the elements that match, in document order
+    fn ids(selector: &str) -> Vec<String> {
+        let (_dom, document) = html::extract_dom(DOC).unwrap();
+        let selector = Selector::parse(selector).unwrap();
+        select(&document, &selector).iter()
+            .map(|node| match &node.data {
+                NodeData::Element { attrs, .. } => html::find_attr(attrs, "id").unwrap_or_default(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn error(selector: &str) -> String {
+        format!("{:#}", Selector::parse(selector).unwrap_err())
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(error("").contains("expected a selector, found the end"));
+        assert!(error("p,").contains("expected a selector, found the end"));
+        assert!(error("p >").contains("expected a selector, found the end"));
+        assert!(error("p!").contains("unexpected '!'"));
+        assert!(error("[title").contains("unterminated attribute selector"));
+        assert!(error("[title=\"foo]").contains("unterminated string"));
+        assert!(error("[title%=foo]").contains("unexpected '%' in attribute selector"));
+        assert!(error("[title^foo]").contains("expected '=', found 'f'"));
+        assert!(error("p:hover").contains("unsupported pseudo-class ':hover'"));
+        assert!(error("p:not(.a").contains("unterminated :not()"));
+        assert!(error("#").contains("expected a name, found the end"));
+        assert!(error("p!").starts_with("invalid selector 'p!'"));
+    }
+
+    #[test]
+    fn simple_selectors() {
+        assert_eq!(ids("H1"), ["title"]);
+        assert_eq!(ids("#p2"), ["p2"]);
+        assert_eq!(ids(".content"), ["main"]);
+        assert_eq!(ids(".cont"), Vec::<String>::new());
+        assert_eq!(ids("div.post#main"), ["main"]);
+        assert_eq!(ids("p, li"), ["p1", "p2", "p3", "p4", "only"]);
+        assert_eq!(ids("*[lang]"), ["p1", "p2"]);
+    }
+
+    #[test]
+    fn combinators() {
+        assert_eq!(ids("div p"), ["p1", "p2", "p3", "p4"]);
+        assert_eq!(ids("div > p"), ["p1", "p2", "p4"]);
+        assert_eq!(ids("h1 + p"), ["p1"]);
+        assert_eq!(ids("section+p"), ["p4"]);
+        assert_eq!(ids("h1 ~ p"), ["p1", "p2", "p4"]);
+        assert_eq!(ids("#p2 ~ *"), ["s1", "p4"]);
+        assert_eq!(ids("body > div section > p"), ["p3"]);
+    }
+
+    #[test]
+    fn attribute_operators() {
+        assert_eq!(ids("[lang=en]"), ["p2"]);
+        assert_eq!(ids("[title~=foo]"), ["p2"]);
+        assert_eq!(ids("[lang|=en]"), ["p1", "p2"]);
+        assert_eq!(ids("[title^=foo]"), ["p2", "p3"]);
+        assert_eq!(ids("[title$=foo]"), ["p4"]);
+        assert_eq!(ids("[title*=oba]"), ["p3"]);
+        assert_eq!(ids("[title='foo bar']"), ["p2"]);
+        assert_eq!(ids("[ title = \"foo bar\" ]"), ["p2"]);
+    }
+
+    #[test]
+    fn empty_attribute_values() {
+        assert_eq!(ids("[data-x]"), ["p1"]);
+        assert_eq!(ids("[data-x='']"), ["p1"]);
+        // An empty word, prefix, suffix or substring matches nothing
+        assert_eq!(ids("[data-x~='']"), Vec::<String>::new());
+        assert_eq!(ids("[title^='']"), Vec::<String>::new());
+        assert_eq!(ids("[title$='']"), Vec::<String>::new());
+        assert_eq!(ids("[title*='']"), Vec::<String>::new());
+        assert_eq!(ids("[data-x|='']"), ["p1"]);
+    }
+
+    #[test]
+    fn pseudo_classes() {
+        assert_eq!(ids("div > :first-child"), ["title"]);
+        assert_eq!(ids("div > p:last-child"), ["p4"]);
+        assert_eq!(ids("body :only-child"), ["p3", "only"]);
+        assert_eq!(ids("div > p:not(#p1, [title^=bar])"), ["p2"]);
+    }
+}
@@ -0,0 +1,621 @@
+//! Conversion between the HTTP cache and WARC files, the format of web
+//! archiving tools like wget, Heritrix and the Wayback Machine.
+//!
+//! Export writes a request and a response record for each cached URL, with
+//! the headers recorded when it was fetched, and a redirect response for each
+//! redirect that led to it. Import reads the responses back, along with their
+//! requests and redirects, so pages can be restored from an archive dump.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use log::{info, debug, warn};
+use anyhow::{Result, Context, bail};
+use flate2::Compression;
+use flate2::read::{MultiGzDecoder, GzDecoder, DeflateDecoder};
+use flate2::write::GzEncoder;
+use reqwest::StatusCode;
+use url::Url;
+use crate::date::DateTime;
+use crate::http_cache::{HttpCache, CacheMeta, Redirect, content_hash, url_hash};
+
+static SOFTWARE: &'static str = concat!("rust-doc-tool/", env!("CARGO_PKG_VERSION"));
+static MAX_REDIRECTS: usize = 10;
+
+/// Writes every cached URL to a WARC file, gzipped record by record if the
+/// file name ends in `.gz`.
+pub fn export(cache: &HttpCache, file: &Path) -> Result<()> {
+    let gzip = file.extension().map(|ext| ext == "gz").unwrap_or(false);
+    let mut out = WarcWriter {
+        out: File::create(file).context("creating warc file")?,
+        gzip,
+    };
+
+    let info = format!("software: {}\r\nformat: WARC File Format 1.0\r\n", SOFTWARE);
+    out.write_record(&[
+        ("WARC-Type", "warcinfo".to_string()),
+        ("WARC-Record-ID", record_id(&["warcinfo", &file.display().to_string()])),
+        ("WARC-Date", DateTime::from_secs(now()).iso8601()),
+        ("Content-Type", "application/warc-fields".to_string()),
+    ], info.as_bytes())?;
+
+    let mut count = 0;
+    let mut skipped = 0;
+    for entry in cache.entries()? {
+        let (entry, meta) = match entry {
+            Ok(entry) => match (&entry.meta, entry.size) {
+                (Ok(Some(meta)), Some(_)) => {
+                    let meta = meta.clone();
+                    (entry, meta)
+                }
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            },
+            Err(_) => continue,
+        };
+        let body = cache.read_entry(&entry.hash)?;
+        write_entry(&mut out, &meta, &body)?;
+        count += 1;
+    }
+
+    info!("exported {} urls to {}", count, file.display());
+    if skipped > 0 {
+        info!("skipped {} entries without metadata", skipped);
+    }
+    Ok(())
+}
+
+fn write_entry(out: &mut WarcWriter, meta: &CacheMeta, body: &[u8]) -> Result<()> {
+    let date = DateTime::from_secs(meta.fetched_at).iso8601();
+    let fetched_at = meta.fetched_at.to_string();
+
+    // The chain of redirects, as responses with just a location
+    let target = meta.final_url.clone().unwrap_or_else(|| meta.url.clone());
+    let hops: Vec<&Url> = meta.redirects.iter().map(|r| &r.url)
+        .chain(Some(&target))
+        .collect();
+    for (redirect, next) in meta.redirects.iter().zip(hops.iter().skip(1)) {
+        let response = format!("{}\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                               status_line(redirect.status), next);
+        out.write_record(&[
+            ("WARC-Type", "response".to_string()),
+            ("WARC-Record-ID", record_id(&["response", redirect.url.as_str(), &fetched_at])),
+            ("WARC-Date", date.clone()),
+            ("WARC-Target-URI", redirect.url.to_string()),
+            ("Content-Type", "application/http;msgtype=response".to_string()),
+        ], response.as_bytes())?;
+    }
+
+    let request_id = record_id(&["request", target.as_str(), &fetched_at]);
+    let response_id = record_id(&["response", target.as_str(), &fetched_at]);
+
+    let mut request = format!("GET {} HTTP/1.1\r\n", request_target(&target));
+    if let Some(host) = target.host_str() {
+        match target.port() {
+            Some(port) => request.push_str(&format!("Host: {}:{}\r\n", host, port)),
+            None => request.push_str(&format!("Host: {}\r\n", host)),
+        }
+    }
+    for (name, value) in &meta.request_headers {
+        if !name.eq_ignore_ascii_case("host") {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    request.push_str("\r\n");
+    out.write_record(&[
+        ("WARC-Type", "request".to_string()),
+        ("WARC-Record-ID", request_id.clone()),
+        ("WARC-Date", date.clone()),
+        ("WARC-Target-URI", target.to_string()),
+        ("WARC-Concurrent-To", response_id.clone()),
+        ("Content-Type", "application/http;msgtype=request".to_string()),
+    ], request.as_bytes())?;
+
+    let mut headers = meta.response_headers.clone();
+    if headers.is_empty() {
+        // Recorded before headers were kept
+        let known = &[
+            ("content-type", &meta.content_type),
+            ("etag", &meta.etag),
+            ("last-modified", &meta.last_modified),
+        ];
+        for (name, value) in known {
+            if let Some(value) = value {
+                headers.push((name.to_string(), value.clone()));
+            }
+        }
+    }
+    // The body is stored as delivered, without a transfer encoding
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("transfer-encoding"));
+    match headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("content-length")) {
+        Some((_, len)) => *len = body.len().to_string(),
+        None => headers.push(("content-length".to_string(), body.len().to_string())),
+    }
+    let mut response = format!("{}\r\n", status_line(meta.status));
+    for (name, value) in &headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    out.write_record(&[
+        ("WARC-Type", "response".to_string()),
+        ("WARC-Record-ID", response_id),
+        ("WARC-Date", date),
+        ("WARC-Target-URI", target.to_string()),
+        ("WARC-Concurrent-To", request_id),
+        ("Content-Type", "application/http;msgtype=response".to_string()),
+    ], &response)?;
+
+    Ok(())
+}
+
+struct WarcWriter {
+    out: File,
+    gzip: bool,
+}
+
+impl WarcWriter {
+    fn write_record(&mut self, headers: &[(&str, String)], block: &[u8]) -> Result<()> {
+        let mut record = b"WARC/1.0\r\n".to_vec();
+        for (name, value) in headers {
+            record.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        record.extend_from_slice(format!("Content-Length: {}\r\n\r\n", block.len()).as_bytes());
+        record.extend_from_slice(block);
+        record.extend_from_slice(b"\r\n\r\n");
+
+        if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&record)?;
+            record = encoder.finish()?;
+        }
+        self.out.write_all(&record)
+            .context("writing warc file")
+    }
+}
+
+/// Adds the responses in a WARC file, gzipped or not, to the cache. URLs
+/// already cached are only replaced by responses archived more recently.
+pub fn import(cache: &HttpCache, file: &Path) -> Result<()> {
+    let mut input = File::open(file)
+        .context("opening warc file")?;
+    let mut magic = [0; 2];
+    let gzip = input.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    input.seek(SeekFrom::Start(0))
+        .context("reading warc file")?;
+    // Archive dumps can be many gigabytes, so records are read one at a time
+    let input: Box<dyn Read> = if gzip {
+        Box::new(MultiGzDecoder::new(input))
+    } else {
+        Box::new(input)
+    };
+    let mut records = RecordReader {
+        input: BufReader::new(input),
+    };
+
+    let mut requests = BTreeMap::new();
+    let mut redirects = BTreeMap::new();
+    // URLs whose archived response is in the cache, either imported or kept
+    // because the cached copy is newer
+    let mut stored: BTreeMap<Url, Stored> = BTreeMap::new();
+    let mut unsuccessful = 0;
+    let mut skipped = 0;
+
+    loop {
+        let record = match records.next_record() {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("skipping bad warc record: {:#}", e);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let type_ = record.header("WARC-Type").unwrap_or_default();
+        let is_http = record.header("Content-Type")
+            .map(|t| t.starts_with("application/http"))
+            .unwrap_or(false);
+        if !is_http || (type_ != "request" && type_ != "response") {
+            continue;
+        }
+        let url = match record.header("WARC-Target-URI")
+            .and_then(|uri| Url::parse(uri.trim_matches(|c| c == '<' || c == '>')).ok()) {
+            Some(url) => url,
+            None => {
+                warn!("skipping {} record without a target uri", type_);
+                skipped += 1;
+                continue;
+            }
+        };
+        let message = match parse_http(&record.block) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("skipping {} record for {}: {:#}", type_, url, e);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        if type_ == "request" {
+            let id = record.header("WARC-Record-ID").unwrap_or_default();
+            // The host is part of the URL, not something the cache records
+            let headers: Vec<_> = message.headers.into_iter()
+                .filter(|(name, _)| !name.eq_ignore_ascii_case("host"))
+                .collect();
+            // Responses find their request by either record's link
+            if let Some(response_id) = record.header("WARC-Concurrent-To") {
+                requests.insert(response_id.to_string(), headers.clone());
+            }
+            requests.insert(id.to_string(), headers);
+            continue;
+        }
+
+        let status = match message.status {
+            Some(status) => status,
+            None => {
+                warn!("skipping response for {} without a status", url);
+                skipped += 1;
+                continue;
+            }
+        };
+        let date = record.header("WARC-Date")
+            .and_then(DateTime::parse_iso8601)
+            .and_then(|date| date.to_secs())
+            .unwrap_or_else(now);
+        if (300..400).contains(&status) {
+            if let Some(location) = find_header(&message.headers, "location") {
+                if let Ok(location) = url.join(location) {
+                    redirects.insert(url, Redirect { url: location, status });
+                }
+            }
+            continue;
+        }
+        if !(200..300).contains(&status) {
+            debug!("skipping {} response for {}", status, url);
+            unsuccessful += 1;
+            continue;
+        }
+        if stored.get(&url).map(|s| date <= s.date).unwrap_or(false) {
+            continue;
+        }
+        let body = match decode_body(&message.headers, message.body) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("skipping response for {}: {:#}", url, e);
+                skipped += 1;
+                continue;
+            }
+        };
+        let headers = message.headers.into_iter()
+            .filter(|(name, _)| {
+                !["transfer-encoding", "content-encoding", "content-length"].iter()
+                    .any(|decoded| name.eq_ignore_ascii_case(decoded))
+            })
+            .chain(Some(("content-length".to_string(), body.len().to_string())))
+            .collect();
+        let response = Response {
+            id: record.header("WARC-Record-ID").unwrap_or_default().to_string(),
+            request_id: record.header("WARC-Concurrent-To").map(|id| id.to_string()),
+            date,
+            status,
+            headers,
+            body,
+        };
+
+        let mut meta = response.meta(&url, &url, Vec::new());
+        let request_headers = response.request_headers(&requests);
+        let has_request = request_headers.is_some();
+        meta.request_headers = request_headers.unwrap_or_default();
+        let existing = cache.meta(&url).ok().flatten();
+        let imported = !existing.map(|e| e.fetched_at >= meta.fetched_at).unwrap_or(false);
+        if imported {
+            cache.insert(&response.body, &meta)?;
+        }
+        stored.insert(url, Stored {
+            date,
+            imported,
+            id: response.id,
+            request_id: response.request_id,
+            has_request,
+        });
+    }
+
+    // Requests can come after their responses
+    for (url, stored) in &stored {
+        if !stored.imported || stored.has_request {
+            continue;
+        }
+        let request_headers = stored.request_id.as_ref()
+            .and_then(|id| requests.get(id))
+            .or_else(|| requests.get(&stored.id));
+        if let (Some(request_headers), Some(mut meta)) = (request_headers, cache.meta(url)?) {
+            meta.request_headers = request_headers.clone();
+            let body = cache.read_entry(&url_hash(url))?;
+            cache.insert(&body, &meta)?;
+        }
+    }
+
+    let mut imported = stored.values().filter(|s| s.imported).count();
+    let mut kept = stored.len() - imported;
+
+    // URLs that redirect to an archived response are cached as that response,
+    // like when fetched
+    for from in redirects.keys() {
+        let mut chain = Vec::new();
+        let mut current = from.clone();
+        while let Some(redirect) = redirects.get(&current) {
+            if chain.len() >= MAX_REDIRECTS {
+                break;
+            }
+            chain.push(Redirect { url: current, status: redirect.status });
+            current = redirect.url.clone();
+        }
+        if !stored.contains_key(&current) {
+            continue;
+        }
+        let mut meta = match cache.meta(&current)? {
+            Some(meta) => meta,
+            None => continue,
+        };
+        meta.url = from.clone();
+        meta.final_url = Some(current.clone());
+        meta.redirects = chain;
+        let existing = cache.meta(from).ok().flatten();
+        if existing.map(|e| e.fetched_at >= meta.fetched_at).unwrap_or(false) {
+            kept += 1;
+            continue;
+        }
+        let body = cache.read_entry(&url_hash(&current))?;
+        cache.insert(&body, &meta)?;
+        imported += 1;
+    }
+
+    info!("imported {} urls, kept {} already cached", imported, kept);
+    if unsuccessful > 0 {
+        info!("skipped {} unsuccessful responses", unsuccessful);
+    }
+    if skipped > 0 {
+        warn!("skipped {} malformed records", skipped);
+    }
+    Ok(())
+}
+
+/// An archived response that is in the cache, or would be if the cache's
+/// copy weren't newer
+struct Stored {
+    date: u64,
+    imported: bool,
+    id: String,
+    request_id: Option<String>,
+    /// Whether its request had been read when it was stored
+    has_request: bool,
+}
+
+/// An archived successful response
+struct Response {
+    id: String,
+    request_id: Option<String>,
+    date: u64,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn meta(&self, url: &Url, final_url: &Url, redirects: Vec<Redirect>) -> CacheMeta {
+        let header = |name| find_header(&self.headers, name).map(|v| v.to_string());
+        CacheMeta {
+            url: url.clone(),
+            fetched_at: self.date,
+            status: self.status,
+            etag: header("etag"),
+            last_modified: header("last-modified"),
+            content_type: header("content-type"),
+            raw: true,
+            body_hash: Some(content_hash(&self.body)),
+            final_url: Some(final_url.clone()),
+            request_headers: Vec::new(),
+            response_headers: self.headers.clone(),
+            snapshot: None,
+            redirects,
+        }
+    }
+
+    fn request_headers(&self, requests: &BTreeMap<String, Vec<(String, String)>>) -> Option<Vec<(String, String)>> {
+        self.request_id.as_ref()
+            .and_then(|id| requests.get(id))
+            .or_else(|| requests.get(&self.id))
+            .cloned()
+    }
+}
+
+struct Record {
+    headers: Vec<(String, String)>,
+    block: Vec<u8>,
+}
+
+impl Record {
+    fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+struct RecordReader<R> {
+    input: R,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    /// Reads the next record, or `None` at the end. After an error, the next
+    /// call skips ahead to the next record's version line.
+    fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if self.input.read_until(b'\n', &mut line)? == 0 {
+                return Ok(None);
+            }
+            if line.starts_with(b"WARC/1.") {
+                break;
+            }
+        }
+
+        let mut head = Vec::new();
+        loop {
+            line.clear();
+            if self.input.read_until(b'\n', &mut line)? == 0 {
+                bail!("truncated warc record");
+            }
+            if line == b"\r\n" || line == b"\n" {
+                break;
+            }
+            head.extend_from_slice(&line);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let headers = parse_headers(head.lines());
+        let len: usize = find_header(&headers, "Content-Length")
+            .and_then(|len| len.trim().parse().ok())
+            .context("warc record without a content length")?;
+        let mut block = Vec::new();
+        (&mut self.input).take(len as u64).read_to_end(&mut block)?;
+        if block.len() < len {
+            bail!("truncated warc record");
+        }
+        Ok(Some(Record { headers, block }))
+    }
+}
+
+struct HttpMessage<'a> {
+    /// The status of a response
+    status: Option<u16>,
+    headers: Vec<(String, String)>,
+    body: &'a [u8],
+}
+
+fn parse_http(block: &[u8]) -> Result<HttpMessage<'_>> {
+    let (head, body) = split_head(block)
+        .context("truncated http message")?;
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+    let start = lines.next().unwrap_or_default();
+    let status = if start.starts_with("HTTP/") {
+        let status = start.split_whitespace().nth(1)
+            .and_then(|status| status.parse().ok())
+            .with_context(|| format!("bad status line {:?}", start))?;
+        Some(status)
+    } else {
+        None
+    };
+    Ok(HttpMessage {
+        status,
+        headers: parse_headers(lines),
+        body,
+    })
+}
+
+/// Undoes the transfer and content encodings of a body, as the cache stores
+/// bodies as reqwest delivers them
+fn decode_body(headers: &[(String, String)], body: &[u8]) -> Result<Vec<u8>> {
+    let chunked = find_header(headers, "transfer-encoding")
+        .map(|te| te.to_ascii_lowercase().contains("chunked"))
+        .unwrap_or(false);
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        body.to_vec()
+    };
+
+    let encoding = find_header(headers, "content-encoding")
+        .map(|ce| ce.trim().to_ascii_lowercase());
+    let mut decoded = Vec::new();
+    match encoding.as_deref() {
+        Some("gzip") | Some("x-gzip") => {
+            GzDecoder::new(&body[..]).read_to_end(&mut decoded)
+                .context("decompressing gzip body")?;
+        }
+        Some("deflate") => {
+            DeflateDecoder::new(&body[..]).read_to_end(&mut decoded)
+                .context("decompressing deflate body")?;
+        }
+        _ => {
+            decoded = body;
+        }
+    }
+    Ok(decoded)
+}
+
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n")
+            .context("truncated chunk size")?;
+        let size = String::from_utf8_lossy(&data[..line_end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .with_context(|| format!("bad chunk size {:?}", size))?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size {
+            bail!("truncated chunk");
+        }
+        body.extend_from_slice(&data[..size]);
+        data = data[size..].strip_prefix(b"\r\n").unwrap_or(&data[size..]);
+    }
+}
+
+/// Splits a message at the blank line after its headers
+fn split_head(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+        return Some((&data[..pos], &data[pos + 4..]));
+    }
+    data.windows(2).position(|w| w == b"\n\n")
+        .map(|pos| (&data[..pos], &data[pos + 2..]))
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<(String, String)> {
+    lines.filter_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        Some((name.to_string(), value.to_string()))
+    }).collect()
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn status_line(status: u16) -> String {
+    let reason = StatusCode::from_u16(status).ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("");
+    format!("HTTP/1.1 {} {}", status, reason)
+}
+
+fn request_target(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+/// A record ID derived from the record's identity, so exports are repeatable
+fn record_id(parts: &[&str]) -> String {
+    let hash = content_hash(parts.join(" ").as_bytes());
+    format!("<urn:uuid:{}-{}-{}-{}-{}>",
+            &hash[0..8], &hash[8..12], &hash[12..16], &hash[16..20], &hash[20..32])
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}